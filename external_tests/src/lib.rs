@@ -29,4 +29,32 @@ mod tests {
         );
         assert_eq!(record, vec![1, 0, 1, 0, 1, 0, 1, 0]);
     }
+
+    #[async_std::test]
+    async fn async_std_timeout() {
+        let res = enjoin::race!(
+            {
+                wait_for(Duration::from_millis(500)).await;
+                "done"
+            },
+            timeout(Duration::from_millis(10), enjoin::timer::AsyncStd) => "timed out",
+        );
+        assert_eq!(res, "timed out");
+    }
+
+    #[tokio::test]
+    async fn tokio_timeout() {
+        let mut slow = None;
+        'outer: loop {
+            enjoin::join!(
+                {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                } on_cancel {
+                    slow = Some("a");
+                },
+                timeout(Duration::from_millis(10), enjoin::timer::Tokio) => break 'outer,
+            );
+        }
+        assert_eq!(slow, Some("a"));
+    }
 }
@@ -0,0 +1,42 @@
+use syn::{parse::ParseStream, token, visit_mut::VisitMut, Expr, ExprBlock, Pat, PatIdent, Token};
+
+/// The `pattern = { block } => handler` form of a race branch.
+pub(crate) struct Arm {
+    pub pat: Pat,
+    pub handler: Expr,
+}
+
+/// Parse the handler of an arm (or of the `else` arm).
+/// Returns whether a comma is needed after it, as in a `match` arm.
+pub(crate) fn parse_handler(input: ParseStream) -> syn::Result<(Expr, bool)> {
+    if input.peek(token::Brace) {
+        let block: ExprBlock = input.parse()?;
+        Ok((Expr::Block(block), false))
+    } else {
+        Ok((input.parse()?, true))
+    }
+}
+
+/// Make a pattern suitable for checking a match against a reference,
+/// without moving out of or mutably borrowing the referenced value.
+pub(crate) fn pattern_for_check(pat: &Pat) -> Pat {
+    let mut pat = pat.to_owned();
+    PatternCleaner.visit_pat_mut(&mut pat);
+    pat
+}
+
+struct PatternCleaner;
+impl VisitMut for PatternCleaner {
+    fn visit_pat_ident_mut(&mut self, i: &mut PatIdent) {
+        i.by_ref = None;
+        i.mutability = None;
+        syn::visit_mut::visit_pat_ident_mut(self, i);
+    }
+    fn visit_expr_mut(&mut self, _i: &mut Expr) {}
+}
+
+/// Whether the input continues with a plain (possibly labeled) block
+/// rather than an arm.
+pub(crate) fn peek_block(input: ParseStream) -> bool {
+    input.peek(token::Brace) || (input.peek(syn::Lifetime) && input.peek2(Token![:]))
+}
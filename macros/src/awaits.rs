@@ -1,26 +1,343 @@
-use proc_macro2::Ident;
-use syn::{parse_quote, visit_mut::VisitMut, Expr, ExprBlock};
-
-pub fn replace_awaits(
-    blocks: &mut Vec<ExprBlock>,
-    borrows_tuple_name: &Ident,
-    borrows_cell_name: &Ident,
-) {
-    let mut replacer = AwaitReplacer {
-        borrows_tuple_name,
-        borrows_cell_name,
-    };
+use proc_macro2::Span;
+use syn::{
+    parse_quote, spanned::Spanned, visit::Visit, visit_mut::VisitMut, Block, Expr, ExprBlock,
+    ExprPath, Ident, Pat, Stmt,
+};
+
+use crate::{
+    captures::SharedCell,
+    nested_macros::{self, CONCURRENT, YIELDING},
+};
+
+/// Make each block borrow the cells it uses, only around the statements that use them,
+/// and release the borrows while awaiting.
+pub fn replace_awaits(blocks: &mut [ExprBlock], cells: &[SharedCell]) -> syn::Result<()> {
+    blocks
+        .iter_mut()
+        .try_for_each(|block| borrow_in_block(&mut block.block, cells))?;
+    // Nested async blocks, async closures, and closures stored in variables
+    // might run while their block is elsewhere, so they borrow on their own.
+    // (This includes the blocks of nested macros of ours, which stand in as async blocks.)
+    let mut nested = NestedBorrower { cells, error: None };
     blocks.iter_mut().for_each(|block| {
-        replacer.visit_expr_block_mut(block);
-        block.block.stmts.insert(
-            0,
-            parse_quote!(let mut #borrows_tuple_name = ::std::cell::RefCell::borrow_mut(&#borrows_cell_name);),
-        );
+        nested.visit_expr_block_mut(block);
     });
+    nested.error.map_or(Ok(()), Err)
+}
+
+struct NestedBorrower<'a> {
+    cells: &'a [SharedCell],
+    error: Option<syn::Error>,
+}
+impl<'a> NestedBorrower<'a> {
+    fn borrow_in_block(&mut self, block: &mut Block) {
+        if let Err(e) = borrow_in_block(block, self.cells) {
+            match &mut self.error {
+                Some(error) => error.combine(e),
+                None => self.error = Some(e),
+            }
+        }
+    }
+    fn borrow_in_closure(&mut self, i: &mut syn::ExprClosure) {
+        let uses_cells = |body: &Expr| {
+            let stmt = Stmt::Expr(body.to_owned(), None);
+            self.cells.iter().any(|cell| uses_guard(&stmt, &cell.guard))
+        };
+        if !matches!(&*i.body, Expr::Block(_)) && uses_cells(&i.body) {
+            let body = &i.body;
+            *i.body = parse_quote!({ #body });
+        }
+        if let Expr::Block(body) = &mut *i.body {
+            self.borrow_in_block(&mut body.block);
+        }
+        syn::visit_mut::visit_expr_closure_mut(self, i);
+    }
 }
+impl<'a> VisitMut for NestedBorrower<'a> {
+    fn visit_item_mut(&mut self, _i: &mut syn::Item) {}
+
+    fn visit_expr_async_mut(&mut self, i: &mut syn::ExprAsync) {
+        self.borrow_in_block(&mut i.block);
+        syn::visit_mut::visit_expr_async_mut(self, i);
+    }
+
+    fn visit_local_mut(&mut self, i: &mut syn::Local) {
+        if let Some(syn::LocalInit { expr, diverge, .. }) = &mut i.init {
+            if let Expr::Closure(closure) = &mut **expr {
+                self.borrow_in_closure(closure);
+                if let Some((_, diverge)) = diverge {
+                    self.visit_expr_mut(diverge);
+                }
+                return;
+            }
+        }
+        syn::visit_mut::visit_local_mut(self, i);
+    }
+
+    fn visit_expr_closure_mut(&mut self, i: &mut syn::ExprClosure) {
+        // Other closures run right there, within the borrows of their statement.
+        if i.asyncness.is_some() {
+            self.borrow_in_closure(i);
+        } else {
+            syn::visit_mut::visit_expr_closure_mut(self, i);
+        }
+    }
+}
+fn borrow_in_block(block: &mut Block, cells: &[SharedCell]) -> syn::Result<()> {
+    for cell in cells.iter() {
+        check_held_borrows(&block.stmts, cell)?;
+    }
+    let stmts = std::mem::take(&mut block.stmts);
+    let last_stmt = stmts.len().saturating_sub(1);
+
+    // For each cell, the runs of consecutive top-level statements it is borrowed across.
+    let mut regions = Vec::new();
+    for cell in cells.iter() {
+        let mut run: Option<(usize, usize)> = None;
+        for (idx, stmt) in stmts.iter().enumerate() {
+            if !uses_guard(stmt, &cell.guard) {
+                continue;
+            }
+            // What a `let` binds might keep borrowing the capture, so keep the borrow to the end.
+            let end = if matches!(stmt, Stmt::Local(_)) {
+                last_stmt
+            } else {
+                idx
+            };
+            run = match run {
+                Some((first, last)) if last + 1 >= idx => Some((first, last.max(end))),
+                Some(finished) => {
+                    regions.push((cell, finished.0, finished.1));
+                    Some((idx, end))
+                }
+                None => Some((idx, end)),
+            };
+        }
+        if let Some((first, last)) = run {
+            regions.push((cell, first, last));
+        }
+    }
+
+    for (idx, mut stmt) in stmts.into_iter().enumerate() {
+        for (cell, _, _) in regions.iter().filter(|(_, first, _)| *first == idx) {
+            let SharedCell { cell, guard, name } = cell;
+            block.stmts.push(parse_quote!(
+                let mut #guard = ::enjoin::__private::borrow_mut(#cell, #name);
+            ));
+        }
+        let live = regions
+            .iter()
+            .filter(|(_, first, last)| (*first..=*last).contains(&idx))
+            .map(|(cell, _, _)| *cell)
+            .collect::<Vec<_>>();
+        if !live.is_empty() {
+            AwaitReplacer { live: &live }.visit_stmt_mut(&mut stmt);
+        }
+        block.stmts.push(stmt);
+        for (cell, _, _) in regions
+            .iter()
+            .filter(|(_, _, last)| *last == idx && idx != last_stmt)
+        {
+            let guard = &cell.guard;
+            block.stmts.push(parse_quote!(::core::mem::drop(#guard);));
+        }
+    }
+    Ok(())
+}
+
+/// Catch the obvious cases of a borrow of a shared capture being held across an await,
+/// like `let r = &mut x; f().await; use(r);`, which would otherwise be a confusing
+/// borrow checker error about our guards.
+fn check_held_borrows(stmts: &[Stmt], cell: &SharedCell) -> syn::Result<()> {
+    for (idx, stmt) in stmts.iter().enumerate() {
+        let Stmt::Local(local) = stmt else {
+            continue;
+        };
+        let Some(init) = &local.init else {
+            continue;
+        };
+        if !borrows_capture(&init.expr, &cell.guard) {
+            continue;
+        }
+        let pat = match &local.pat {
+            Pat::Type(pt) => &*pt.pat,
+            pat => pat,
+        };
+        let Pat::Ident(binding) = pat else {
+            continue;
+        };
+        let borrow = &binding.ident;
+        let mut await_point = None;
+        for later in stmts[idx + 1..].iter() {
+            if let Some(await_span) = await_point {
+                if let Some(use_span) = find_use(later, borrow) {
+                    let capture = &cell.name;
+                    let mut error = syn::Error::new(
+                        await_span,
+                        format!(
+                            "`{borrow}` borrows `{capture}`, which is shared between blocks, and is still used after this `.await`. \
+                            Another block may use `{capture}` while this one waits, so the borrow can't be held across the `.await`. \
+                            End the borrow before the `.await`, and borrow `{capture}` again after it."
+                        ),
+                    );
+                    error.combine(syn::Error::new(
+                        borrow.span(),
+                        format!("`{borrow}` borrows `{capture}` here"),
+                    ));
+                    error.combine(syn::Error::new(
+                        use_span,
+                        format!("`{borrow}` is used here, after the `.await`"),
+                    ));
+                    return Err(error);
+                }
+            } else {
+                await_point = find_await(later);
+            }
+            // Shadowed by a new variable of the same name.
+            if let Stmt::Local(l) = later {
+                if matches!(&l.pat, Pat::Ident(p) if p.ident == *borrow) {
+                    break;
+                }
+            }
+        }
+    }
+    // Check the bodies of loops, `if`s, and other blocks nested in these statements.
+    // Closures and async blocks are checked when they get their own borrows.
+    struct NestedChecker<'a> {
+        cell: &'a SharedCell,
+        result: syn::Result<()>,
+    }
+    impl<'a, 'ast> Visit<'ast> for NestedChecker<'a> {
+        fn visit_item(&mut self, _i: &'ast syn::Item) {}
+        fn visit_expr_async(&mut self, _i: &'ast syn::ExprAsync) {}
+        fn visit_expr_closure(&mut self, _i: &'ast syn::ExprClosure) {}
+        fn visit_block(&mut self, i: &'ast Block) {
+            if self.result.is_ok() {
+                self.result = check_held_borrows(&i.stmts, self.cell);
+            }
+        }
+    }
+    let mut checker = NestedChecker {
+        cell,
+        result: Ok(()),
+    };
+    stmts.iter().for_each(|stmt| checker.visit_stmt(stmt));
+    checker.result
+}
+
+/// Whether the expression is obviously a borrow of the shared capture,
+/// like `&mut x`, `&x.field`, or `x.iter_mut()`.
+fn borrows_capture(expr: &Expr, guard: &Ident) -> bool {
+    fn is_capture(expr: &Expr, guard: &Ident) -> bool {
+        match expr {
+            Expr::Paren(e) => is_capture(&e.expr, guard),
+            Expr::Unary(e) if matches!(e.op, syn::UnOp::Deref(_)) => is_capture(&e.expr, guard),
+            Expr::Field(e) => is_capture(&e.base, guard),
+            Expr::Index(e) => is_capture(&e.expr, guard),
+            Expr::Path(p) => p.path.is_ident(guard),
+            _ => false,
+        }
+    }
+    match expr {
+        Expr::Reference(r) => is_capture(&r.expr, guard),
+        Expr::MethodCall(mc) => {
+            let method = mc.method.to_string();
+            (method.ends_with("_mut")
+                || matches!(
+                    method.as_str(),
+                    "iter" | "as_ref" | "as_slice" | "as_str" | "first" | "last" | "get"
+                ))
+                && is_capture(&mc.receiver, guard)
+        }
+        _ => false,
+    }
+}
+
+/// Where the statement awaits, if it does.
+fn find_await(stmt: &Stmt) -> Option<Span> {
+    struct AwaitFinder {
+        found: Option<Span>,
+    }
+    impl<'ast> Visit<'ast> for AwaitFinder {
+        fn visit_item(&mut self, _i: &'ast syn::Item) {}
+        fn visit_expr_async(&mut self, _i: &'ast syn::ExprAsync) {}
+        fn visit_expr_closure(&mut self, _i: &'ast syn::ExprClosure) {}
+        fn visit_expr(&mut self, i: &'ast Expr) {
+            if self.found.is_some() {
+                return;
+            }
+            match i {
+                Expr::Await(aw) => self.found = Some(aw.await_token.span),
+                Expr::Macro(mac) if nested_macros::might_await(&mac.mac) => {
+                    self.found = Some(mac.span())
+                }
+                _ => match nested_macros::stand_in_kind(i) {
+                    Some(YIELDING) | Some(CONCURRENT) => self.found = Some(i.span()),
+                    _ => syn::visit::visit_expr(self, i),
+                },
+            }
+        }
+    }
+    let mut finder = AwaitFinder { found: None };
+    finder.visit_stmt(stmt);
+    finder.found
+}
+
+/// Where the statement uses the variable, if it does.
+fn find_use(stmt: &Stmt, ident: &Ident) -> Option<Span> {
+    struct UseFinder<'a> {
+        ident: &'a Ident,
+        found: Option<Span>,
+    }
+    impl<'a, 'ast> Visit<'ast> for UseFinder<'a> {
+        fn visit_expr_path(&mut self, i: &'ast ExprPath) {
+            if self.found.is_none() && i.path.is_ident(self.ident) {
+                self.found = Some(i.span());
+            }
+        }
+    }
+    let mut finder = UseFinder { ident, found: None };
+    finder.visit_stmt(stmt);
+    finder.found
+}
+
+fn uses_guard(stmt: &Stmt, guard: &Ident) -> bool {
+    struct GuardFinder<'a> {
+        guard: &'a Ident,
+        found: bool,
+    }
+    impl<'a, 'ast> Visit<'ast> for GuardFinder<'a> {
+        fn visit_expr_path(&mut self, i: &'ast ExprPath) {
+            self.found |= i.path.is_ident(self.guard);
+        }
+        // Nested async blocks, async closures, and stored closures borrow on their own.
+        fn visit_expr_async(&mut self, _i: &'ast syn::ExprAsync) {}
+        fn visit_expr_closure(&mut self, i: &'ast syn::ExprClosure) {
+            if i.asyncness.is_none() {
+                syn::visit::visit_expr_closure(self, i);
+            }
+        }
+        fn visit_local(&mut self, i: &'ast syn::Local) {
+            match &i.init {
+                Some(init) if matches!(&*init.expr, Expr::Closure(_)) => {
+                    if let Some((_, diverge)) = &init.diverge {
+                        self.visit_expr(diverge);
+                    }
+                }
+                _ => syn::visit::visit_local(self, i),
+            }
+        }
+    }
+    let mut finder = GuardFinder {
+        guard,
+        found: false,
+    };
+    finder.visit_stmt(stmt);
+    finder.found
+}
+
 struct AwaitReplacer<'a> {
-    borrows_tuple_name: &'a Ident,
-    borrows_cell_name: &'a Ident,
+    live: &'a [&'a SharedCell],
 }
 impl<'a> VisitMut for AwaitReplacer<'a> {
     fn visit_item_mut(&mut self, _i: &mut syn::Item) {}
@@ -28,21 +345,54 @@ impl<'a> VisitMut for AwaitReplacer<'a> {
     fn visit_expr_closure_mut(&mut self, _i: &mut syn::ExprClosure) {}
 
     fn visit_expr_mut(&mut self, i: &mut Expr) {
-        if let Expr::Await(aw) = i {
-            let borrows_name = self.borrows_tuple_name;
-            let borrows_cell_name = self.borrows_cell_name;
-            let base = &aw.base;
-            *i = parse_quote!(
-                (
+        let guards = self.live.iter().map(|cell| &cell.guard);
+        let reborrows = self.live.iter().map(|SharedCell { cell, guard, name }| {
+            quote::quote!(#guard = ::enjoin::__private::borrow_mut(#cell, #name);)
+        });
+        match i {
+            Expr::Await(aw) => {
+                self.visit_expr_mut(&mut aw.base);
+                let base = &aw.base;
+                *i = parse_quote!(
                     (
-                        #base,
-                        {::core::mem::drop( #borrows_name );},
-                    ).0.await,
-                    {#borrows_name = ::std::cell::RefCell::borrow_mut(&#borrows_cell_name);}
-                ).0
-            );
-        } else {
-            syn::visit_mut::visit_expr_mut(self, i);
+                        (
+                            #base,
+                            {#(::core::mem::drop( #guards );)*},
+                        ).0.await,
+                        {#(#reborrows)*}
+                    ).0
+                );
+            }
+            // Macros that await inside are released around as a whole.
+            Expr::Macro(mac) if nested_macros::might_await(&mac.mac) => {
+                *i = parse_quote!(
+                    (
+                        (
+                            {#(::core::mem::drop( #guards );)*},
+                            #i,
+                        ).1,
+                        {#(#reborrows)*}
+                    ).0
+                );
+            }
+            _ => match nested_macros::stand_in_kind(i) {
+                Some(kind) if kind == YIELDING || kind == CONCURRENT => {
+                    if kind == YIELDING {
+                        // The arguments are evaluated before the macro awaits.
+                        syn::visit_mut::visit_expr_mut(self, i);
+                    }
+                    *i = parse_quote!(
+                        (
+                            (
+                                {#(::core::mem::drop( #guards );)*},
+                                #i,
+                            ).1,
+                            {#(#reborrows)*}
+                        ).0
+                    );
+                }
+                _ => syn::visit_mut::visit_expr_mut(self, i),
+            },
         }
     }
 }
@@ -80,7 +80,7 @@ impl<'a> VisitMut for BreakReplacer<'a> {
         let (variant_name, _) = match self.found.entry(esc) {
             std::collections::hash_map::Entry::Occupied(occ) => occ.into_mut(),
             std::collections::hash_map::Entry::Vacant(vac) => {
-                let name = vac.key().variant_name(&self.private_ident);
+                let name = vac.key().variant_name(self.private_ident);
                 vac.insert((name, expr.is_some()))
             }
         };
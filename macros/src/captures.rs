@@ -4,27 +4,122 @@ use std::{
 };
 
 use proc_macro2::{Ident, TokenStream};
-use quote::quote;
-use syn::{parse_quote, visit::Visit, visit_mut::VisitMut, Expr, ExprBlock, Member};
+use quote::{format_ident, quote};
+use syn::{
+    parse_quote, punctuated::Punctuated, spanned::Spanned, visit::Visit, visit_mut::VisitMut,
+    BinOp, Expr, ExprBlock, Member, Token,
+};
+
+use crate::options::{Options, ShareEntry, ShareKind};
 
 struct CaptureInfo<'a> {
     capture: Capture,
     block_id: usize,
     immutable: bool,
-    exprs: Vec<&'a syn::Expr>,
+    /// Whether some uses are of fields of the capture rather than the capture itself.
+    projected: bool,
+    uses: Vec<CaptureUse<'a>>,
+}
+
+/// One occurence of a capture.
+struct CaptureUse<'a> {
+    usage: Usage,
+    /// Which block it is in.
+    block_id: usize,
+    expr: &'a Expr,
+    /// The whole `capture = value` or `capture += value` expression, if this is one.
+    assign: Option<&'a Expr>,
+}
+
+/// How a capture is used at one of its occurences.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Usage {
+    /// Used as a value, as in `f(x)` or `x + 1`.
+    Read,
+    /// Borrowed immutably, as in `&x`.
+    Ref,
+    /// Assigned to, as in `x = value`.
+    /// `literal` is whether the value is a literal.
+    Assign { literal: bool },
+    /// Updated with an operator, as in `x += value`.
+    CompoundAssign,
+    /// Anything else that needs the place, such as `&mut x` or `x.method()`.
+    Place,
+}
+
+impl Usage {
+    /// Whether this use works the same on a copy of the value taken out of a `Cell`.
+    fn works_with_cell(self) -> bool {
+        !matches!(self, Usage::Place)
+    }
+    /// Whether this use shows that the value is `Copy`.
+    /// Literals are always `Copy`, and assigning one pins down the type.
+    /// Updating with one doesn't, since non-`Copy` types can implement `AddAssign<u32>`.
+    fn proves_copy(self) -> bool {
+        matches!(self, Usage::Assign { literal: true })
+    }
+}
+
+/// The code to set up the shared captures, to be put before the blocks.
+pub struct Borrows {
+    pub setup: TokenStream,
+    /// The captures that are in a `RefCell`, which the blocks need to borrow.
+    pub cells: Vec<SharedCell>,
+}
+
+/// A `RefCell` holding a mutable reference to one capture.
+pub struct SharedCell {
+    pub cell: Ident,
+    /// The variable each block keeps the borrowed `RefCell` in.
+    pub guard: Ident,
+    /// The capture as written, for panic messages.
+    pub name: String,
 }
 
 pub fn replace_captures_and_generate_borrows(
-    blocks: &mut Vec<ExprBlock>,
-    borrows_tuple_name: &Ident,
+    blocks: &mut [ExprBlock],
+    on_cancel_of: &[usize],
+    repeated: bool,
+    options: &Options,
+    borrows_name: &Ident,
     borrows_cell_name: &Ident,
-) -> Option<TokenStream> {
+    copy_cells_name: &Ident,
+) -> syn::Result<Option<Borrows>> {
+    // With `share(...)`, the listed places are the only captures.
+    let share = options
+        .share
+        .as_deref()
+        .map(|entries| {
+            entries
+                .iter()
+                .map(|entry| Ok((entry, parse_place(&entry.place)?)))
+                .collect::<syn::Result<Vec<_>>>()
+        })
+        .transpose()?;
+    let listed = share.as_ref().map(|share| {
+        share
+            .iter()
+            .map(|(_, place)| place.root.to_owned())
+            .collect::<HashSet<_>>()
+    });
+
+    let num_blocks = blocks.len() - on_cancel_of.len();
     // Visit all the blocks we want to join, figuring out which captures what.
     let block_captures = blocks
         .iter_mut()
         .map(|block| {
             let mut collector = CaptureFinder {
-                locals: Locals::default(),
+                // The `graceful(...)` signal is declared by the macro, for the blocks to use.
+                locals: Locals {
+                    all: options
+                        .graceful
+                        .iter()
+                        .map(|g| g.signal.to_owned())
+                        .collect(),
+                    stack: Vec::new(),
+                },
+                listed: listed.clone(),
+                ref_methods: &options.ref_methods,
                 found: Vec::new(),
             };
             collector.visit_expr_block(block);
@@ -37,26 +132,253 @@ pub fn replace_captures_and_generate_borrows(
         .into_iter()
         .enumerate()
         .flat_map(|(block_id, captures)| {
-            captures
-                .into_iter()
-                .map(move |(capture, immutable, expr)| CaptureInfo {
+            captures.into_iter().map(move |(capture, mut capture_use)| {
+                capture_use.block_id = block_id;
+                CaptureInfo {
                     capture,
                     block_id,
-                    immutable,
-                    exprs: vec![expr],
-                })
+                    immutable: capture_use.usage == Usage::Ref,
+                    projected: false,
+                    uses: vec![capture_use],
+                }
+            })
         })
         .collect::<Vec<_>>();
 
     // Sort it so that captures with ancestor/descendent relationship appear sequentially.
     all_captures.sort_by(|a, b| a.capture.cmp(&b.capture));
 
-    if all_captures.is_empty() {
-        return None;
+    let copy = options
+        .copy
+        .iter()
+        .map(|expr| Ok((parse_place(expr)?, expr)))
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let block_names = BlockNames {
+        repeated,
+        num_blocks,
+        on_cancel_of,
+    };
+    let mut explanations = Vec::new();
+    let captures = match share {
+        Some(share) => listed_captures(&share, &all_captures)?,
+        None => {
+            let mut captures = merge_captures(all_captures);
+            // Keep the ones that are captured by at least 2 blocks (or by a repeated block)
+            // and not known to be immutable.
+            // These are the ones we need to wrap in cells.
+            captures.retain(|info| {
+                let keep = (repeated || info.block_id == usize::MAX) && !info.immutable;
+                if options.explain.is_some() && !keep {
+                    let reason = if info.immutable {
+                        "is only read, so it is borrowed as usual"
+                    } else {
+                        "is not shared between blocks, so it is borrowed as usual"
+                    };
+                    explanations.push(explain(info, &block_names, reason));
+                }
+                keep
+            });
+            captures
+        }
+    };
+
+    // Decide which of them go in a `Cell` instead of the `RefCell`.
+    let mut in_cell = captures
+        .iter()
+        .map(|info| {
+            !info.projected
+                && info.uses.iter().all(|u| u.usage.works_with_cell())
+                && info.uses.iter().any(|u| u.usage.proves_copy())
+        })
+        .collect::<Vec<_>>();
+    for (capture, expr) in copy.iter() {
+        let Some(idx) = captures.iter().position(|info| &info.capture == capture) else {
+            return Err(not_shared_error(expr));
+        };
+        let info = &captures[idx];
+        if let Some(bad) = info
+            .uses
+            .iter()
+            .find(|u| !u.usage.works_with_cell())
+            .map(|u| u.expr)
+            .or_else(|| info.projected.then_some(*expr))
+        {
+            return Err(syn::Error::new(
+                bad.span(),
+                format!(
+                    "`{}` is listed in `copy(...)`, but it is used in a way that needs a reference, so it can't be put in a `Cell`",
+                    quote!(#expr)
+                ),
+            ));
+        }
+        in_cell[idx] = true;
+    }
+
+    if options.explain.is_some() {
+        for (info, in_cell) in captures.iter().zip(in_cell.iter()) {
+            let reason = if *in_cell {
+                "is changed and its type is `Copy`, so it is shared through a `Cell`"
+            } else {
+                "is used mutably, so it is shared through its own `RefCell`"
+            };
+            explanations.push(explain(info, &block_names, reason));
+        }
     }
 
+    // Create the cells, and the expressions with which we will replace the captured occurences in the async blocks.
+    let mut setup = TokenStream::new();
+    let mut shared_cells = Vec::new();
+    let mut replacements = HashMap::new();
+    for (idx, (info, in_cell)) in captures.iter().zip(in_cell).enumerate() {
+        let root = &info.capture.root;
+        let members = info.capture.members.iter().map(|m| &m.member);
+        let borrowed: Expr = if root == "self" && info.capture.members.is_empty() {
+            // `self` might be a `&mut Self` that isn't declared `mut`, or a `mut self` value.
+            // Method syntax reborrows the first and borrows the second.
+            parse_quote!({
+                use ::enjoin::__private::Reborrow as _;
+                self.__enjoin_rb()
+            })
+        } else {
+            parse_quote!(&mut #root #( . #members )*)
+        };
+        if in_cell {
+            let cell = format_ident!("{}_{}", copy_cells_name, idx);
+            setup.extend(quote!(let #cell = ::enjoin::__private::Cell::from_mut(#borrowed);));
+            for u in info.uses.iter() {
+                let replacement = match (u.usage, u.assign) {
+                    (Usage::Assign { .. }, Some(assign)) => {
+                        (assign as *const Expr, Replacement::Set(cell.clone()))
+                    }
+                    (Usage::CompoundAssign, Some(assign)) => {
+                        (assign as *const Expr, Replacement::Update(cell.clone()))
+                    }
+                    _ => (u.expr as *const Expr, Replacement::Get(cell.clone())),
+                };
+                replacements.insert(replacement.0, replacement.1);
+            }
+        } else {
+            let cell = format_ident!("{}_{}", borrows_cell_name, idx);
+            let guard = format_ident!("{}_{}", borrows_name, idx);
+            // Bound by reference, so that `async move` blocks inside the blocks copy the reference
+            // instead of moving the cell.
+            setup.extend(quote!(let #cell = &::enjoin::__private::RefCell::new(#borrowed);));
+            for u in info.uses.iter() {
+                replacements.insert(
+                    u.expr as *const Expr,
+                    Replacement::Expr(parse_quote!( (** #guard) )),
+                );
+            }
+            shared_cells.push(SharedCell {
+                cell,
+                guard,
+                name: info.capture.to_string(),
+            });
+        }
+    }
+
+    let mut replacer = CaptureReplacer {
+        replacements,
+        value_name: format_ident!("{}_value", copy_cells_name),
+        rhs_name: format_ident!("{}_rhs", copy_cells_name),
+    };
+
+    setup.extend(explanations);
+    if !captures.is_empty() {
+        blocks.iter_mut().for_each(|block| {
+            replacer.visit_expr_block_mut(block);
+        });
+    }
+    if !setup.is_empty() {
+        Ok(Some(Borrows {
+            setup,
+            cells: shared_cells,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// How the explanations refer to the blocks.
+struct BlockNames<'a> {
+    /// `join_for!` has one body, run many times.
+    repeated: bool,
+    num_blocks: usize,
+    /// The `on_cancel` code comes after the blocks, and belongs to these ones.
+    on_cancel_of: &'a [usize],
+}
+
+/// With the `explain;` option, tell what was decided about the capture,
+/// as a warning at each use.
+/// (Stable Rust has no way for macros to emit notes, but it does warn when deprecated items are used.)
+fn explain(info: &CaptureInfo, block_names: &BlockNames, reason: &str) -> TokenStream {
+    let used_by = if block_names.repeated {
+        "the body".to_string()
+    } else {
+        let (on_cancels, blocks): (Vec<_>, Vec<_>) = info
+            .uses
+            .iter()
+            .map(|u| u.block_id)
+            .partition(|id| *id >= block_names.num_blocks);
+        let on_cancels = on_cancels
+            .into_iter()
+            .map(|id| block_names.on_cancel_of[id - block_names.num_blocks])
+            .collect();
+        let list = |mut ids: Vec<usize>, one: &str, many: &str| {
+            ids.sort();
+            ids.dedup();
+            match ids.as_slice() {
+                [] => None,
+                [id] => Some(format!("{} {}", one, id + 1)),
+                [init @ .., last] => Some(format!(
+                    "{} {} and {}",
+                    many,
+                    init.iter()
+                        .map(|id| (id + 1).to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    last + 1
+                )),
+            }
+        };
+        [
+            list(blocks, "block", "blocks"),
+            list(
+                on_cancels,
+                "the `on_cancel` of block",
+                "the `on_cancel`s of blocks",
+            ),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" and ")
+    };
+    let note = format!("`{}` is used by {} and {}", info.capture, used_by, reason);
+    info.uses
+        .iter()
+        .map(|u| {
+            let marker = Ident::new("__enjoin_explain", u.expr.span());
+            quote!(
+                {
+                    #[deprecated(note = #note)]
+                    #[allow(non_camel_case_types)]
+                    struct #marker;
+                    let _ = #marker;
+                }
+            )
+        })
+        .collect()
+}
+
+/// Merge the captures found in the blocks, so that each is listed along with all its fields.
+fn merge_captures(all_captures: Vec<CaptureInfo>) -> Vec<CaptureInfo> {
     let mut all_captures = all_captures.into_iter();
-    let mut captures = vec![all_captures.next().unwrap()];
+    let Some(first) = all_captures.next() else {
+        return Vec::new();
+    };
+    let mut captures = vec![first];
 
     for dsc in all_captures {
         let anc = captures.last_mut().unwrap();
@@ -67,57 +389,108 @@ pub fn replace_captures_and_generate_borrows(
             if dsc.block_id != anc.block_id {
                 anc.block_id = usize::MAX;
             }
-            anc.exprs
-                .extend(dsc.exprs.into_iter().map(|ex| access_field(ex, depth_dif)));
+            if depth_dif > 0 {
+                anc.projected = true;
+            }
+            anc.projected |= dsc.projected;
+            anc.uses.extend(dsc.uses.into_iter().map(|u| CaptureUse {
+                usage: u.usage,
+                block_id: u.block_id,
+                expr: access_field(u.expr, depth_dif),
+                assign: u.assign.filter(|_| depth_dif == 0),
+            }));
             anc.immutable &= dsc.immutable;
         } else {
             captures.push(dsc);
         }
     }
 
-    // Filter for ones that are captured by at least 2 blocks and not known to be immutable.
-    // These are the ones we need to wrap in cells.
-    captures.retain(|info| info.block_id == usize::MAX && !info.immutable);
-
-    // Create the expressions for the captures that need to be wrapped.
-    let borrows = captures.iter().map(|info| {
-        let root = &info.capture.root;
-        let members = info.capture.members.iter().map(|m| &m.member);
-        let b: syn::Expr = parse_quote!( #root #( . #members )* );
-        b
-    });
+    captures
+}
 
-    // Create the expression with which we will replace the captured occurences in the async blocks.
-    let replacements = captures
-        .iter()
-        .enumerate()
-        .flat_map(|(idx, info)| {
-            let index = syn::Index::from(idx);
-            info.exprs.iter().map(move |&expr| {
-                (
-                    expr as *const syn::Expr,
-                    parse_quote!( (* #borrows_tuple_name . #index) ),
-                )
+/// The captures listed as `mut` in `share(...)`, with all their uses.
+fn listed_captures<'a>(
+    share: &[(&ShareEntry, Capture)],
+    all_captures: &[CaptureInfo<'a>],
+) -> syn::Result<Vec<CaptureInfo<'a>>> {
+    let mut captures = Vec::new();
+    for (entry, place) in share.iter() {
+        let uses = all_captures
+            .iter()
+            .filter(|info| {
+                info.capture.root == place.root && info.capture.members.starts_with(&place.members)
             })
-        })
-        .collect::<HashMap<_, _>>();
-
-    let mut replacer = CaptureReplacer { replacements };
-
-    // Generate code for building the RefCell.
-    if !captures.is_empty() {
-        let out = quote!(
-            let #borrows_cell_name = ::std::cell::RefCell::new((
-                #(&mut #borrows ,)*
+            .flat_map(|info| {
+                let depth_dif = info.capture.members.len() - place.members.len();
+                info.uses.iter().map(move |u| {
+                    (
+                        depth_dif > 0,
+                        CaptureUse {
+                            usage: u.usage,
+                            block_id: u.block_id,
+                            expr: access_field(u.expr, depth_dif),
+                            assign: u.assign.filter(|_| depth_dif == 0),
+                        },
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        if uses.is_empty() {
+            let place = &entry.place;
+            return Err(syn::Error::new(
+                place.span(),
+                format!(
+                    "`{}` is listed in `share(...)`, but none of the blocks use it",
+                    quote!(#place)
+                ),
             ));
-        );
-        blocks.iter_mut().for_each(|block| {
-            replacer.visit_expr_block_mut(block);
-        });
-        Some(out)
-    } else {
-        None
+        }
+        if entry.kind == ShareKind::Mut {
+            captures.push(CaptureInfo {
+                capture: place.to_owned(),
+                block_id: usize::MAX,
+                immutable: false,
+                projected: uses.iter().any(|(projected, _)| *projected),
+                uses: uses.into_iter().map(|(_, u)| u).collect(),
+            });
+        }
     }
+    Ok(captures)
+}
+
+fn parse_place(expr: &Expr) -> syn::Result<Capture> {
+    match expr {
+        Expr::Path(p) => match p.path.get_ident() {
+            Some(ident) => Ok(Capture {
+                root: ident.to_owned(),
+                members: Vec::new(),
+            }),
+            None => Err(syn::Error::new(
+                expr.span(),
+                "expected a variable or a field",
+            )),
+        },
+        Expr::Field(f) => parse_place(&f.base).map(|mut c| {
+            c.members.push(CaptureMember {
+                member: f.member.to_owned(),
+            });
+            c
+        }),
+        _ => Err(syn::Error::new(
+            expr.span(),
+            "expected a variable or a field",
+        )),
+    }
+}
+
+fn not_shared_error(expr: &Expr) -> syn::Error {
+    syn::Error::new(
+        expr.span(),
+        format!(
+            "`{}` is listed in `copy(...)`, but it is not mutably shared between blocks",
+            quote!(#expr)
+        ),
+    )
 }
 
 fn access_field(ex: &syn::Expr, depth: usize) -> &syn::Expr {
@@ -125,7 +498,7 @@ fn access_field(ex: &syn::Expr, depth: usize) -> &syn::Expr {
         ex
     } else {
         match ex {
-            Expr::Field(f) => access_field(&*f.base, depth - 1),
+            Expr::Field(f) => access_field(&f.base, depth - 1),
             _ => panic!("no field to access"),
         }
     }
@@ -137,28 +510,47 @@ struct Capture {
     members: Vec<CaptureMember>,
 }
 
+impl std::fmt::Display for Capture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.root)?;
+        for m in self.members.iter() {
+            match &m.member {
+                Member::Named(ident) => write!(f, ".{}", ident)?,
+                Member::Unnamed(index) => write!(f, ".{}", index.index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, PartialEq, Eq)]
 struct CaptureMember {
     member: Member,
 }
 
 impl PartialOrd for CaptureMember {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match (&self.member, &other.member) {
-            (Member::Named(this), Member::Named(other)) => this.partial_cmp(other),
-            (Member::Named(_), Member::Unnamed(_)) => Some(Ordering::Greater),
-            (Member::Unnamed(_), Member::Named(_)) => Some(Ordering::Less),
-            (Member::Unnamed(this), Member::Unnamed(other)) => this.index.partial_cmp(&other.index),
-        }
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 impl Ord for CaptureMember {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+        match (&self.member, &other.member) {
+            (Member::Named(this), Member::Named(other)) => this.cmp(other),
+            (Member::Named(_), Member::Unnamed(_)) => Ordering::Greater,
+            (Member::Unnamed(_), Member::Named(_)) => Ordering::Less,
+            (Member::Unnamed(this), Member::Unnamed(other)) => this.index.cmp(&other.index),
+        }
     }
 }
 
-fn get_capture_field(i: &syn::Expr, locals: &Locals) -> Option<Capture> {
+/// `listed` is the variables listed in `share(...)`, if given.
+/// Otherwise, guess which variables are captures.
+fn get_capture_field(
+    i: &syn::Expr,
+    locals: &Locals,
+    listed: Option<&HashSet<Ident>>,
+) -> Option<Capture> {
     match i {
         Expr::Path(p) if p.path.segments.len() == 1 => {
             if let Some(syn::PathSegment {
@@ -166,18 +558,26 @@ fn get_capture_field(i: &syn::Expr, locals: &Locals) -> Option<Capture> {
                 ident,
             }) = p.path.segments.first()
             {
-                let s = ident.to_string();
-                (!s.chars().next().unwrap().is_ascii_uppercase() && !locals.contains(&ident)).then(
-                    || Capture {
-                        root: ident.to_owned(),
-                        members: Vec::new(),
-                    },
-                )
+                let is_capture = match listed {
+                    Some(listed) => listed.contains(ident),
+                    None => !ident
+                        .to_string()
+                        .chars()
+                        .next()
+                        .unwrap()
+                        .is_ascii_uppercase(),
+                };
+                // Our own variables, left by a surrounding macro for a nested one.
+                let is_ours = ident.to_string().starts_with("__enjoin");
+                (is_capture && !is_ours && !locals.contains(ident)).then(|| Capture {
+                    root: ident.to_owned(),
+                    members: Vec::new(),
+                })
             } else {
                 None
             }
         }
-        Expr::Field(f) => get_capture_field(&*f.base, locals).map(|mut c| {
+        Expr::Field(f) => get_capture_field(&f.base, locals, listed).map(|mut c| {
             c.members.push(CaptureMember {
                 member: f.member.to_owned(),
             });
@@ -221,7 +621,156 @@ impl<'ast> Visit<'ast> for Locals {
 /// and which are captured from outside.
 struct CaptureFinder<'ast> {
     locals: Locals,
-    found: Vec<(Capture, bool, &'ast syn::Expr)>,
+    listed: Option<HashSet<Ident>>,
+    /// Methods listed in `ref_methods(...)`, on top of [`REF_METHODS`].
+    ref_methods: &'ast [Ident],
+    found: Vec<(Capture, CaptureUse<'ast>)>,
+}
+
+/// Methods of std types that take `&self`, and so only need to borrow their receiver immutably.
+const REF_METHODS: &[&str] = &[
+    "as_slice",
+    "as_str",
+    "binary_search",
+    "bytes",
+    "capacity",
+    "chars",
+    "chunks",
+    "clone",
+    "cmp",
+    "contains",
+    "contains_key",
+    "ends_with",
+    "eq",
+    "first",
+    "get",
+    "is_empty",
+    "is_err",
+    "is_none",
+    "is_ok",
+    "is_some",
+    "iter",
+    "keys",
+    "last",
+    "len",
+    "lines",
+    "ne",
+    "partial_cmp",
+    "split",
+    "starts_with",
+    "to_owned",
+    "to_string",
+    "to_vec",
+    "trim",
+    "values",
+    "windows",
+];
+
+impl<'ast> CaptureFinder<'ast> {
+    /// Visit the condition of an `if`, `while`, or match guard,
+    /// adding what its `let`s bind (possibly in a `&&` chain) to the current scope.
+    fn visit_condition(&mut self, cond: &'ast Expr) {
+        match cond {
+            Expr::Let(el) => {
+                self.visit_expr(&el.expr);
+                self.locals.add(&el.pat);
+            }
+            Expr::Binary(b) if matches!(b.op, BinOp::And(_)) => {
+                self.visit_condition(&b.left);
+                self.visit_condition(&b.right);
+            }
+            _ => self.visit_expr(cond),
+        }
+    }
+
+    /// Whether the method only needs to borrow its receiver immutably.
+    fn is_ref_method(&self, method: &Ident) -> bool {
+        REF_METHODS.iter().any(|name| method == name) || self.ref_methods.contains(method)
+    }
+}
+
+impl<'ast> CaptureFinder<'ast> {
+    /// Record `ex` if it is a capture. Returns whether it is.
+    fn found(&mut self, ex: &'ast Expr, usage: Usage, assign: Option<&'ast Expr>) -> bool {
+        if let Some((base, indices)) = index_projection(ex) {
+            // Indexing projects into the place, but which part can't be known from the syntax,
+            // so it counts as a use of the whole base.
+            // Reading through an index only needs `Index::index`, which takes `&self`.
+            let usage = match usage {
+                Usage::Read | Usage::Ref => Usage::Ref,
+                _ => Usage::Place,
+            };
+            self.found(base, usage, None);
+            indices.into_iter().for_each(|index| self.visit_expr(index));
+            return true;
+        }
+        if let Some(capt) = get_capture_field(ex, &self.locals, self.listed.as_ref()) {
+            self.found.push((
+                capt,
+                CaptureUse {
+                    usage,
+                    block_id: 0,
+                    expr: ex,
+                    assign,
+                },
+            ));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn is_literal(expr: &Expr, numeric_only: bool) -> bool {
+    match expr {
+        Expr::Lit(l) => {
+            !numeric_only
+                || matches!(
+                    l.lit,
+                    syn::Lit::Int(_) | syn::Lit::Float(_) | syn::Lit::Bool(_)
+                )
+        }
+        Expr::Paren(p) => is_literal(&p.expr, numeric_only),
+        Expr::Unary(u) if matches!(u.op, syn::UnOp::Neg(_)) => is_literal(&u.expr, true),
+        _ => false,
+    }
+}
+
+/// If `ex` is a place like `a.b[i].c[j]`, the base before the first index (`a.b`)
+/// and all the index expressions.
+fn index_projection(ex: &Expr) -> Option<(&Expr, Vec<&Expr>)> {
+    let mut indices = Vec::new();
+    let mut base = None;
+    let mut current = ex;
+    loop {
+        match current {
+            Expr::Field(f) => current = &f.base,
+            Expr::Index(ix) => {
+                indices.push(&*ix.index);
+                base = Some(&*ix.expr);
+                current = &ix.expr;
+            }
+            Expr::Path(_) => break,
+            _ => return None,
+        }
+    }
+    base.map(|base| (base, indices))
+}
+
+fn is_compound_assign(op: &BinOp) -> bool {
+    matches!(
+        op,
+        BinOp::AddAssign(_)
+            | BinOp::SubAssign(_)
+            | BinOp::MulAssign(_)
+            | BinOp::DivAssign(_)
+            | BinOp::RemAssign(_)
+            | BinOp::BitXorAssign(_)
+            | BinOp::BitAndAssign(_)
+            | BinOp::BitOrAssign(_)
+            | BinOp::ShlAssign(_)
+            | BinOp::ShrAssign(_)
+    )
 }
 
 impl<'ast> Visit<'ast> for CaptureFinder<'ast> {
@@ -230,34 +779,24 @@ impl<'ast> Visit<'ast> for CaptureFinder<'ast> {
 
     // These expressions create new bindings.
     fn visit_expr_if(&mut self, i: &'ast syn::ExprIf) {
-        if let Expr::Let(el) = &*i.cond {
-            self.visit_expr(&el.expr);
-            self.locals.push_stack();
-            self.locals.add(&el.pat);
-            self.visit_block(&i.then_branch);
-            self.locals.pop_stack();
-            if let Some((_, eb)) = &i.else_branch {
-                self.visit_expr(&*eb);
-            }
-        } else {
-            syn::visit::visit_expr_if(self, i);
+        self.locals.push_stack();
+        self.visit_condition(&i.cond);
+        self.visit_block(&i.then_branch);
+        self.locals.pop_stack();
+        if let Some((_, eb)) = &i.else_branch {
+            self.visit_expr(eb);
         }
     }
     fn visit_expr_while(&mut self, i: &'ast syn::ExprWhile) {
-        if let Expr::Let(el) = &*i.cond {
-            self.visit_expr(&el.expr);
-            self.locals.push_stack();
-            self.locals.add(&el.pat);
-            self.visit_block(&i.body);
-            self.locals.pop_stack();
-        } else {
-            syn::visit::visit_expr_while(self, i);
-        }
+        self.locals.push_stack();
+        self.visit_condition(&i.cond);
+        self.visit_block(&i.body);
+        self.locals.pop_stack();
     }
     fn visit_expr_for_loop(&mut self, i: &'ast syn::ExprForLoop) {
         self.visit_expr(&i.expr);
         self.locals.push_stack();
-        self.locals.add(&*i.pat);
+        self.locals.add(&i.pat);
         self.visit_block(&i.body);
         self.locals.pop_stack();
     }
@@ -265,7 +804,7 @@ impl<'ast> Visit<'ast> for CaptureFinder<'ast> {
         self.locals.push_stack();
         self.locals.add(&i.pat);
         if let Some((_, guard)) = &i.guard {
-            syn::visit::visit_expr(self, &*guard);
+            self.visit_condition(guard);
         }
         syn::visit::visit_expr(self, &i.body);
         self.locals.pop_stack();
@@ -273,13 +812,41 @@ impl<'ast> Visit<'ast> for CaptureFinder<'ast> {
     fn visit_expr_closure(&mut self, i: &'ast syn::ExprClosure) {
         self.locals.push_stack();
         i.inputs.iter().for_each(|arg| self.locals.add(arg));
-        self.visit_expr(&*i.body);
+        self.visit_expr(&i.body);
         self.locals.pop_stack();
     }
     fn visit_local(&mut self, i: &'ast syn::Local) {
+        // For `let ... else`, the bindings aren't in scope in the `else` block either.
         syn::visit::visit_local(self, i);
         self.locals.add(&i.pat);
     }
+    fn visit_stmt_macro(&mut self, i: &'ast syn::StmtMacro) {
+        // `pin_mut!(fut);` and `tokio::pin!(fut);` shadow their arguments.
+        let is_pin = i
+            .mac
+            .path
+            .segments
+            .last()
+            .is_some_and(|last| last.ident == "pin" || last.ident == "pin_mut");
+        if !is_pin {
+            return;
+        }
+        if let Ok(idents) = i
+            .mac
+            .parse_body_with(Punctuated::<Ident, Token![,]>::parse_terminated)
+        {
+            idents
+                .iter()
+                .for_each(|ident| self.locals.add(&parse_quote!(#ident)));
+        } else if let Ok(stmts) = i.mac.parse_body_with(syn::Block::parse_within) {
+            // `tokio::pin! { let fut = ...; }`
+            for stmt in stmts.iter() {
+                if let syn::Stmt::Local(local) = stmt {
+                    self.locals.add(&local.pat);
+                }
+            }
+        }
+    }
 
     fn visit_block(&mut self, i: &'ast syn::Block) {
         self.locals.push_stack();
@@ -289,14 +856,51 @@ impl<'ast> Visit<'ast> for CaptureFinder<'ast> {
 
     // Find variable expressions that we need to modify.
     fn visit_expr(&mut self, i: &'ast Expr) {
-        let (ex, immut) = match i {
-            Expr::Path(_) | Expr::Field(_) => (i, false),
-            Expr::Reference(r) => (&*r.expr, r.mutability.is_none()),
-            Expr::Call(c) => {
+        let (ex, usage) = match i {
+            Expr::Path(_) | Expr::Field(_) | Expr::Index(_) => (i, Usage::Read),
+            Expr::Reference(r) => (
+                &*r.expr,
+                if r.mutability.is_none() {
+                    Usage::Ref
+                } else {
+                    Usage::Place
+                },
+            ),
+            Expr::Assign(a) => {
+                let usage = Usage::Assign {
+                    literal: is_literal(&a.right, false),
+                };
+                if !self.found(&a.left, usage, Some(i)) {
+                    self.visit_expr(&a.left);
+                }
+                self.visit_expr(&a.right);
+                return;
+            }
+            Expr::Binary(b) if is_compound_assign(&b.op) => {
+                if !self.found(&b.left, Usage::CompoundAssign, Some(i)) {
+                    self.visit_expr(&b.left);
+                }
+                self.visit_expr(&b.right);
+                return;
+            }
+            Expr::MethodCall(m) => {
+                let usage = if self.is_ref_method(&m.method) {
+                    Usage::Ref
+                } else {
+                    Usage::Place
+                };
+                if !self.found(&m.receiver, usage, None) {
+                    self.visit_expr(&m.receiver);
+                }
+                m.args.iter().for_each(|arg| self.visit_expr(arg));
+                return;
+            }
+            // Without a list, guess that a function being called isn't a capture.
+            Expr::Call(c) if self.listed.is_none() => {
                 match &*c.func {
                     Expr::Path(p) if p.path.segments.len() == 1 => {}
                     _ => {
-                        syn::visit::visit_expr(self, &*c.func);
+                        syn::visit::visit_expr(self, &c.func);
                     }
                 }
                 c.args.iter().for_each(|arg| {
@@ -309,23 +913,52 @@ impl<'ast> Visit<'ast> for CaptureFinder<'ast> {
                 return;
             }
         };
-        if let Some(capt) = get_capture_field(ex, &self.locals) {
-            self.found.push((capt, immut, ex));
-        } else {
+        if !self.found(ex, usage, None) {
             syn::visit::visit_expr(self, i);
         }
     }
 }
 
+enum Replacement {
+    Expr(Expr),
+    /// Read the value out of the cell.
+    Get(Ident),
+    /// Replace a `capture = value` assignment.
+    Set(Ident),
+    /// Replace a `capture += value` assignment.
+    Update(Ident),
+}
+
 struct CaptureReplacer {
-    replacements: HashMap<*const syn::Expr, syn::Expr>,
+    replacements: HashMap<*const syn::Expr, Replacement>,
+    value_name: Ident,
+    rhs_name: Ident,
 }
 
 impl VisitMut for CaptureReplacer {
     fn visit_expr_mut(&mut self, i: &mut Expr) {
         syn::visit_mut::visit_expr_mut(self, i);
-        if let Some(rep) = self.replacements.remove(&(i as *const _)) {
-            *i = rep;
-        }
+        let Some(rep) = self.replacements.remove(&(i as *const _)) else {
+            return;
+        };
+        *i = match (rep, &*i) {
+            (Replacement::Expr(rep), _) => rep,
+            (Replacement::Get(cell), _) => parse_quote!(::enjoin::__private::Cell::get(#cell)),
+            (Replacement::Set(cell), Expr::Assign(a)) => {
+                let right = &a.right;
+                parse_quote!(::enjoin::__private::Cell::set(#cell, #right))
+            }
+            (Replacement::Update(cell), Expr::Binary(b)) => {
+                let (right, op) = (&b.right, &b.op);
+                let (value, rhs) = (&self.value_name, &self.rhs_name);
+                parse_quote!({
+                    let #rhs = #right;
+                    let mut #value = ::enjoin::__private::Cell::get(#cell);
+                    #value #op #rhs;
+                    ::enjoin::__private::Cell::set(#cell, #value)
+                })
+            }
+            _ => unreachable!(),
+        };
     }
 }
@@ -0,0 +1,362 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse::Parse, parse_quote, spanned::Spanned, Block, Expr, ExprBlock, Ident, Pat, Stmt, Token,
+};
+
+use crate::{
+    options::{Options, PollOrder},
+    tokens_left, transform_blocks, Transformed,
+};
+
+/// `for pattern in iterator { body }`,
+/// where the body is run concurrently for every item.
+pub(crate) struct ForInput {
+    options: Options,
+    pat: Pat,
+    iter: Expr,
+    body: Block,
+    /// How many token trees of the input are left after the body.
+    pub(crate) body_end: usize,
+}
+
+impl Parse for ForInput {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let options = Options::parse(input)?;
+        input.parse::<Token![for]>()?;
+        let pat = Pat::parse_multi_with_leading_vert(input)?;
+        input.parse::<Token![in]>()?;
+        let iter = Expr::parse_without_eager_brace(input)?;
+        let body = input.parse()?;
+        Ok(Self {
+            options,
+            pat,
+            iter,
+            body,
+            body_end: tokens_left(input),
+        })
+    }
+}
+
+impl ForInput {
+    pub fn generate(self, make_borrows: bool) -> syn::Result<TokenStream> {
+        let private_ident = Ident::new("__enjoin", Span::mixed_site());
+        let Self {
+            options,
+            pat,
+            iter,
+            body,
+            body_end: _,
+        } = self;
+
+        if let Some(on_escape) = &options.on_escape {
+            return Err(syn::Error::new(
+                on_escape.span(),
+                "`on_escape` is only supported in `join!`, `try_join!`, and `try_race!`",
+            ));
+        }
+        if let Some(graceful) = &options.graceful {
+            return Err(syn::Error::new(
+                graceful.signal.span(),
+                "`graceful(...)` is not supported in `join_for!`",
+            ));
+        }
+
+        // Each copy of the body gets its item through this cell.
+        // Moving the cell (which is never `Copy`) makes the async block take the item by value.
+        let item = format_ident!("{}_item", private_ident);
+        let body = &body.stmts;
+        let mut block: ExprBlock = parse_quote!({
+            let #pat = ();
+            #(#body)*
+        });
+        // Verbatim, so that the passes don't see the cell as a captured variable.
+        if let Stmt::Local(local) = &mut block.block.stmts[0] {
+            *local.init.as_mut().unwrap().expr =
+                Expr::Verbatim(quote!(::core::cell::Cell::into_inner(#item)));
+        }
+        let mut blocks = [block];
+        let Transformed {
+            borrows,
+            return_type,
+            escape_arms,
+        } = transform_blocks(
+            &mut blocks,
+            &[],
+            make_borrows,
+            true,
+            &options,
+            &private_ident,
+        )?;
+        let [block] = blocks;
+
+        let output_type = format_ident!("{}_OutputEnum", private_ident);
+        let keep_ty = format_ident!("{}_Keep", private_ident);
+        let poll_cx = format_ident!("{}_poll_cx", private_ident);
+        let pinned_futs = format_ident!("{}_pinned_futs", private_ident);
+        let num_left = format_ident!("{}_num_left", private_ident);
+        let outputs = format_ident!("{}_ouputs", private_ident);
+        let wakers = format_ident!("{}_wakers", private_ident);
+        let next_start = format_ident!("{}_next_start", private_ident);
+
+        let (wakers_state, register_waker, branch_cx, take_woken) = if options.branch_wakers {
+            (
+                quote!(let #wakers = ::enjoin::__private::BranchWakers::new(#num_left);),
+                quote!(::enjoin::__private::BranchWakers::register(&#wakers, ::core::task::Context::waker(#poll_cx));),
+                quote!(&mut ::core::task::Context::from_waker(
+                    ::enjoin::__private::BranchWakers::waker(&#wakers, index)
+                )),
+                // This must come last, so that the woken flag is only cleared if we do poll.
+                quote!(&& ::enjoin::__private::BranchWakers::take_woken(&#wakers, index)),
+            )
+        } else {
+            (quote!(), quote!(), quote!(#poll_cx), quote!())
+        };
+        let (order_state, start) = match options.poll_order {
+            PollOrder::Biased => (quote!(), quote!(0)),
+            PollOrder::Rotate => (
+                quote!(let mut #next_start: usize = 0;),
+                quote!({
+                    let start = #next_start;
+                    #next_start = if start + 1 >= len { 0 } else { start + 1 };
+                    start
+                }),
+            ),
+        };
+
+        if let Some(limit) = options.limit {
+            return Ok(generate_limited(
+                &private_ident,
+                Generated {
+                    limit,
+                    iter,
+                    block,
+                    borrows,
+                    return_type,
+                    escape_arms,
+                    wakers_state,
+                    register_waker,
+                    branch_cx,
+                    order_state,
+                    start,
+                },
+            ));
+        }
+
+        let poller = quote!(
+            ::core::future::poll_fn(|#poll_cx| {
+                #register_waker
+                let len = ::enjoin::__private::Vec::len(&#pinned_futs);
+                let start: usize = #start;
+                for offset in 0..len {
+                    let index = (start + offset) % len;
+                    if ::core::option::Option::is_none(& #outputs[index]) #take_woken {
+                        match ::core::future::Future::poll(::core::pin::Pin::as_mut(&mut #pinned_futs[index]), #branch_cx) {
+                            ::core::task::Poll::Ready (r) => match #output_type :: convert_breaking (r) {
+                                ::core::ops::ControlFlow::Continue (v) => {
+                                    #num_left -= 1;
+                                    #outputs[index] = ::core::option::Option::Some(v);
+                                },
+                                ::core::ops::ControlFlow::Break (b) => return ::core::task::Poll::Ready (b),
+                            },
+                            ::core::task::Poll::Pending => {}
+                        }
+                    }
+                }
+                if #num_left == 0 {
+                    ::core::task::Poll::Ready (#output_type :: #keep_ty (
+                        ::core::iter::Iterator::collect::<::enjoin::__private::Vec<_>>(
+                            ::core::iter::Iterator::map(
+                                ::enjoin::__private::Vec::drain(&mut #outputs, ..),
+                                ::core::option::Option::unwrap,
+                            )
+                        )
+                    ))
+                }
+                else {
+                    ::core::task::Poll::Pending
+                }
+            })
+        );
+
+        Ok(quote! {
+            {
+                #borrows
+                #return_type
+                let mut #pinned_futs = ::enjoin::__private::Vec::new();
+                #[allow(clippy::await_holding_refcell_ref)]
+                for #item in #iter {
+                    let #item = ::core::cell::Cell::new(#item);
+                    ::enjoin::__private::Vec::push(
+                        &mut #pinned_futs,
+                        ::enjoin::__private::Box::pin(async {
+                            #[allow(unreachable_code)]
+                            #output_type :: #keep_ty (
+                                #[warn(unreachable_code)]
+                                #block
+                            )
+                        }),
+                    );
+                }
+                let mut #num_left = ::enjoin::__private::Vec::len(&#pinned_futs);
+                let mut #outputs = ::enjoin::__private::Vec::new();
+                ::enjoin::__private::Vec::resize_with(&mut #outputs, #num_left, || ::core::option::Option::None);
+                #wakers_state
+                #order_state
+                match #poller .await {
+                    #output_type :: #keep_ty (e) => e,
+                    #escape_arms
+                }
+            }
+        })
+    }
+}
+
+/// The parts of the expansion shared with the `limit = N;` form.
+struct Generated {
+    limit: Expr,
+    iter: Expr,
+    block: ExprBlock,
+    borrows: Option<TokenStream>,
+    return_type: TokenStream,
+    escape_arms: TokenStream,
+    wakers_state: TokenStream,
+    register_waker: TokenStream,
+    branch_cx: TokenStream,
+    order_state: TokenStream,
+    start: TokenStream,
+}
+
+/// With `limit = N;`, the copies of the body live in `N` slots.
+/// When a copy finishes, the next item is started in its slot
+/// and polled right away.
+fn generate_limited(private_ident: &Ident, generated: Generated) -> TokenStream {
+    let Generated {
+        limit,
+        iter,
+        block,
+        borrows,
+        return_type,
+        escape_arms,
+        wakers_state,
+        register_waker,
+        branch_cx,
+        order_state,
+        start,
+    } = generated;
+    let output_type = format_ident!("{}_OutputEnum", private_ident);
+    let keep_ty = format_ident!("{}_Keep", private_ident);
+    let poll_cx = format_ident!("{}_poll_cx", private_ident);
+    let item = format_ident!("{}_item", private_ident);
+    let items = format_ident!("{}_items", private_ident);
+    let make_fut = format_ident!("{}_make_fut", private_ident);
+    let slots = format_ident!("{}_slots", private_ident);
+    let num_left = format_ident!("{}_num_left", private_ident);
+    let outputs = format_ident!("{}_ouputs", private_ident);
+    let wakers = format_ident!("{}_wakers", private_ident);
+    let limit_value = format_ident!("{}_limit", private_ident);
+
+    // The branch wakers are per slot.
+    // A fresh copy is polled even if its slot wasn't woken.
+    let (fresh_state, should_poll, set_fresh) = if wakers_state.is_empty() {
+        (quote!(), quote!(), quote!())
+    } else {
+        (
+            quote!(let mut fresh = false;),
+            quote!(
+                if !(fresh || ::enjoin::__private::BranchWakers::take_woken(&#wakers, index)) {
+                    break;
+                }
+            ),
+            quote!(fresh = true;),
+        )
+    };
+    let next_fut = quote!(
+        match ::core::iter::Iterator::next(&mut #items) {
+            ::core::option::Option::Some(#item) => {
+                let position = ::enjoin::__private::Vec::len(&#outputs);
+                ::enjoin::__private::Vec::push(&mut #outputs, ::core::option::Option::None);
+                ::core::option::Option::Some((position, #make_fut(#item)))
+            }
+            ::core::option::Option::None => ::core::option::Option::None,
+        }
+    );
+
+    let poller = quote!(
+        ::core::future::poll_fn(|#poll_cx| {
+            #register_waker
+            let len = ::enjoin::__private::Vec::len(&#slots);
+            let start: usize = #start;
+            for offset in 0..len {
+                let index = (start + offset) % len;
+                #fresh_state
+                while let ::core::option::Option::Some((position, fut)) = &mut #slots[index] {
+                    #should_poll
+                    match ::core::future::Future::poll(::core::pin::Pin::as_mut(fut), #branch_cx) {
+                        ::core::task::Poll::Ready (r) => match #output_type :: convert_breaking (r) {
+                            ::core::ops::ControlFlow::Continue (v) => {
+                                #outputs[*position] = ::core::option::Option::Some(v);
+                                #slots[index] = #next_fut;
+                                if ::core::option::Option::is_none(&#slots[index]) {
+                                    #num_left -= 1;
+                                }
+                                #set_fresh
+                            },
+                            ::core::ops::ControlFlow::Break (b) => return ::core::task::Poll::Ready (b),
+                        },
+                        ::core::task::Poll::Pending => break,
+                    }
+                }
+            }
+            if #num_left == 0 {
+                ::core::task::Poll::Ready (#output_type :: #keep_ty (
+                    ::core::iter::Iterator::collect::<::enjoin::__private::Vec<_>>(
+                        ::core::iter::Iterator::map(
+                            ::enjoin::__private::Vec::drain(&mut #outputs, ..),
+                            ::core::option::Option::unwrap,
+                        )
+                    )
+                ))
+            }
+            else {
+                ::core::task::Poll::Pending
+            }
+        })
+    );
+
+    quote! {
+        {
+            #borrows
+            #return_type
+            let #limit_value: usize = #limit;
+            ::core::assert!(#limit_value > 0, "`limit` must be at least 1");
+            let mut #items = ::core::iter::Iterator::fuse(::core::iter::IntoIterator::into_iter(#iter));
+            #[allow(clippy::await_holding_refcell_ref)]
+            let #make_fut = ::enjoin::__private::item_fn(&#items, |#item| {
+                let #item = ::core::cell::Cell::new(#item);
+                ::enjoin::__private::Box::pin(async {
+                    #[allow(unreachable_code)]
+                    #output_type :: #keep_ty (
+                        #[warn(unreachable_code)]
+                        #block
+                    )
+                })
+            });
+            let mut #outputs = ::enjoin::__private::Vec::new();
+            let mut #slots = ::enjoin::__private::Vec::new();
+            while ::enjoin::__private::Vec::len(&#slots) < #limit_value {
+                match #next_fut {
+                    ::core::option::Option::Some(slot) => ::enjoin::__private::Vec::push(&mut #slots, ::core::option::Option::Some(slot)),
+                    ::core::option::Option::None => break,
+                }
+            }
+            let mut #num_left = ::enjoin::__private::Vec::len(&#slots);
+            #wakers_state
+            #order_state
+            match #poller .await {
+                #output_type :: #keep_ty (e) => e,
+                #escape_arms
+            }
+        }
+    }
+}
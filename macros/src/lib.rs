@@ -1,16 +1,27 @@
+mod arms;
 mod awaits;
 mod breaks;
 mod captures;
+mod for_each;
+mod nested_macros;
+mod on_cancel;
+mod options;
+mod timeout;
 mod trys;
 
-use std::{collections::HashMap, iter::repeat};
+use std::collections::HashMap;
 
+use arms::Arm;
 use breaks::{BreakReplacer, Escape};
+use for_each::ForInput;
+use options::{Graceful, Options, PollOrder};
 use proc_macro2::{Span, TokenStream};
 use quote::{format_ident, quote};
 use syn::{
-    parse::Parse, parse_macro_input, parse_quote, punctuated::Punctuated, ExprBlock, Ident, Token,
+    parse::Parse, parse_macro_input, parse_quote, spanned::Spanned, Expr, ExprBlock, Ident, Pat,
+    PatIdent, Token,
 };
+use timeout::Timeout;
 
 /// Run given blocks of async code concurrently.
 /// Use `break`/`continue`/`return`/`?` to jump out.
@@ -18,7 +29,7 @@ use syn::{
 #[proc_macro]
 pub fn join(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as MacroInput);
-    match input.generate(false) {
+    match input.generate(Mode::Join, false) {
         Ok(o) => o.into(),
         Err(e) => e.to_compile_error().into(),
     }
@@ -30,199 +41,984 @@ pub fn join(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 pub fn join_auto_borrow(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as MacroInput);
+    match input.generate(Mode::Join, true) {
+        Ok(o) => o.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Run given blocks of async code concurrently,
+/// returning the value of whichever block finishes first.
+/// The other blocks are dropped.
+/// Blocks may also be given as `tokio::select!`-style
+/// `pattern = { block } => handler` arms, with an optional `else => handler` arm.
+/// Use `break`/`continue`/`return`/`?` to jump out.
+/// See the [crate documentation](https://docs.rs/enjoin/latest/enjoin/).
+#[proc_macro]
+pub fn race(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as MacroInput);
+    match input.generate(Mode::Race, false) {
+        Ok(o) => o.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Run given blocks of async code concurrently.
+/// Each block should evaluate to a `Result`, an `Option`,
+/// or another type implementing `enjoin::polyfill::Try`,
+/// whose residual implements `enjoin::polyfill::Residual`.
+/// If any block evaluates to an error/`None`, the other blocks are dropped
+/// and that error/`None` is returned.
+/// Otherwise, the successful values are returned as a tuple
+/// wrapped in `Ok`/`Some`.
+/// Use `break`/`continue`/`return`/`?` to jump out.
+/// See the [crate documentation](https://docs.rs/enjoin/latest/enjoin/).
+#[proc_macro]
+pub fn try_join(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as MacroInput);
+    match input.generate(Mode::TryJoin, false) {
+        Ok(o) => o.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Run given blocks of async code concurrently.
+/// Each block should evaluate to a `Result`, an `Option`,
+/// or another type implementing `enjoin::polyfill::Try`
+/// (`enjoin::polyfill::Residual` isn't needed here).
+/// The first block to evaluate to a success (`Ok`/`Some`) wins;
+/// its value is returned in `Ok` and the other blocks are dropped.
+/// If every block fails, the failures are returned as a tuple in `Err`.
+/// Use `break`/`continue`/`return`/`?` to jump out.
+/// See the [crate documentation](https://docs.rs/enjoin/latest/enjoin/).
+#[proc_macro]
+pub fn try_race(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as MacroInput);
+    match input.generate(Mode::TryRace, false) {
+        Ok(o) => o.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Run a block of async code concurrently for every item of an iterator,
+/// returning the results in a `Vec`, in order.
+/// Written as `join_for!(for pattern in iterator { ... })`.
+/// Use `break`/`continue`/`return`/`?` to jump out.
+/// See the [crate documentation](https://docs.rs/enjoin/latest/enjoin/).
+#[proc_macro]
+pub fn join_for(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as ForInput);
+    match input.generate(false) {
+        Ok(o) => o.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Everything [join_for!] does,
+/// plus the automatic shared mutable borrowing described in the
+/// [crate documentation](https://docs.rs/enjoin/latest/enjoin/).
+/// Since the body runs many times concurrently,
+/// everything it borrows mutably is shared.
+#[proc_macro]
+pub fn join_for_auto_borrow(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as ForInput);
     match input.generate(true) {
         Ok(o) => o.into(),
         Err(e) => e.to_compile_error().into(),
     }
 }
 
-struct MacroInput(Vec<ExprBlock>);
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Wait for all blocks, returning a tuple of their values.
+    Join,
+    /// Wait for the first block, returning its value.
+    Race,
+    /// Wait for all blocks to succeed or for the first one to fail.
+    TryJoin,
+    /// Wait for the first block to succeed or for all of them to fail.
+    TryRace,
+}
+
+struct MacroInput {
+    options: Options,
+    blocks: Vec<ExprBlock>,
+    /// The `pattern = { block } => handler` arm for each block, if it is one.
+    arms: Vec<Option<Arm>>,
+    /// The name of each block given as `name = { block }`, if it has one.
+    names: Vec<Option<Ident>>,
+    /// The `on_cancel { ... }` code given after each block, if any.
+    on_cancels: Vec<Option<ExprBlock>>,
+    else_handler: Option<(Token![else], Expr)>,
+    timeout: Option<Timeout>,
+    /// How many token trees of the input are left after each block.
+    block_ends: Vec<usize>,
+}
+
+/// How many token trees are left in the input.
+/// Lets `nested_macros` find where the parser took blocks from.
+pub(crate) fn tokens_left(input: syn::parse::ParseStream) -> usize {
+    let mut cursor = input.cursor();
+    let mut count = 0;
+    while let Some((_, next)) = cursor.token_tree() {
+        cursor = next;
+        count += 1;
+    }
+    count
+}
 
 impl Parse for MacroInput {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let blocks: Punctuated<ExprBlock, Token![,]> = Punctuated::parse_terminated(input)?;
-        Ok(Self(blocks.into_iter().collect()))
+        let options = Options::parse(input)?;
+        let mut blocks = Vec::new();
+        let mut arms = Vec::new();
+        let mut names: Vec<Option<Ident>> = Vec::new();
+        let mut on_cancels = Vec::new();
+        let mut else_handler = None;
+        let mut timeout = None;
+        let mut block_ends = Vec::new();
+        while !input.is_empty() {
+            let needs_comma = if input.peek(Token![else]) {
+                let else_token: Token![else] = input.parse()?;
+                if else_handler.is_some() {
+                    return Err(syn::Error::new_spanned(
+                        else_token,
+                        "there can only be one `else` arm",
+                    ));
+                }
+                input.parse::<Token![=>]>()?;
+                let (handler, needs_comma) = arms::parse_handler(input)?;
+                else_handler = Some((else_token, handler));
+                needs_comma
+            } else if timeout::peek(input) {
+                let (parsed, needs_comma) = timeout::parse(input)?;
+                if timeout.is_some() {
+                    return Err(syn::Error::new(
+                        parsed.token.span(),
+                        "there can only be one `timeout`",
+                    ));
+                }
+                timeout = Some(parsed);
+                needs_comma
+            } else if arms::peek_block(input) {
+                blocks.push(input.parse()?);
+                block_ends.push(tokens_left(input));
+                on_cancels.push(on_cancel::parse(input)?);
+                arms.push(None);
+                names.push(None);
+                true
+            } else {
+                let pat = Pat::parse_multi_with_leading_vert(input)?;
+                input.parse::<Token![=]>()?;
+                blocks.push(input.parse()?);
+                block_ends.push(tokens_left(input));
+                on_cancels.push(on_cancel::parse(input)?);
+                if input.peek(Token![=>]) {
+                    input.parse::<Token![=>]>()?;
+                    let (handler, needs_comma) = arms::parse_handler(input)?;
+                    arms.push(Some(Arm { pat, handler }));
+                    names.push(None);
+                    needs_comma
+                } else {
+                    // Without a handler, it's a named block: `name = { block }`.
+                    let name = match pat {
+                        Pat::Ident(PatIdent {
+                            attrs,
+                            by_ref: None,
+                            mutability: None,
+                            ident,
+                            subpat: None,
+                        }) if attrs.is_empty() => ident,
+                        pat => {
+                            return Err(syn::Error::new(
+                                pat.span(),
+                                "expected a name for the block, or `=>` and a handler after it",
+                            ))
+                        }
+                    };
+                    if let Some(first) = names.iter().flatten().find(|n| **n == name) {
+                        let mut error = syn::Error::new(
+                            name.span(),
+                            format!("there is already a block named `{}`", name),
+                        );
+                        error.combine(syn::Error::new(first.span(), "first named here"));
+                        return Err(error);
+                    }
+                    arms.push(None);
+                    names.push(Some(name));
+                    true
+                }
+            };
+            if input.is_empty() {
+                break;
+            }
+            if needs_comma {
+                input.parse::<Token![,]>()?;
+            } else {
+                input.parse::<Option<Token![,]>>()?;
+            }
+        }
+        Ok(Self {
+            options,
+            blocks,
+            arms,
+            names,
+            on_cancels,
+            else_handler,
+            timeout,
+            block_ends,
+        })
     }
 }
 
 impl MacroInput {
-    fn generate(self, make_borrows: bool) -> syn::Result<TokenStream> {
+    fn generate(self, mode: Mode, make_borrows: bool) -> syn::Result<TokenStream> {
         let private_ident = Ident::new("__enjoin", Span::mixed_site());
-        let borrows_tuple = format_ident!("{}_borrows", private_ident);
-        let borrows_cell = format_ident!("{}_borrows_cell", private_ident);
-        let Self(mut blocks) = self;
-        let borrows = if make_borrows {
-            captures::replace_captures_and_generate_borrows(
-                &mut blocks,
-                &borrows_tuple,
-                &borrows_cell,
-            )
+        let Self {
+            options,
+            mut blocks,
+            arms,
+            names,
+            on_cancels,
+            else_handler,
+            timeout,
+            block_ends: _,
+        } = self;
+        let has_arms = arms.iter().any(Option::is_some) || else_handler.is_some();
+        if mode != Mode::Race && has_arms {
+            let span = match (arms.iter().flatten().next(), &else_handler) {
+                (Some(arm), _) => arm.pat.span(),
+                (None, Some((else_token, _))) => else_token.span,
+                (None, None) => unreachable!(),
+            };
+            return Err(syn::Error::new(
+                span,
+                "`pattern = { block } => handler` arms are only supported in `race!`",
+            ));
+        }
+        if let Some(limit) = &options.limit {
+            return Err(syn::Error::new(
+                limit.span(),
+                "`limit` is only supported in `join_for!`",
+            ));
+        }
+        if let (Some(on_escape), Mode::Race) = (&options.on_escape, mode) {
+            return Err(syn::Error::new(
+                on_escape.span(),
+                "`on_escape` is only supported in `join!`, `try_join!`, and `try_race!`",
+            ));
+        }
+        let names = if names.iter().any(Option::is_some) {
+            if !matches!(mode, Mode::Join | Mode::TryJoin) {
+                let name = names.iter().flatten().next().unwrap();
+                return Err(syn::Error::new(
+                    name.span(),
+                    "named blocks are only supported in `join!` and `try_join!`",
+                ));
+            }
+            if let Some(idx) = names.iter().position(Option::is_none) {
+                return Err(syn::Error::new(
+                    blocks[idx].span(),
+                    "this block needs a name too, since the others have one",
+                ));
+            }
+            Some(names.into_iter().flatten().collect::<Vec<_>>())
         } else {
             None
         };
-
+        match mode {
+            Mode::Race | Mode::TryJoin | Mode::TryRace if blocks.is_empty() => {
+                return Err(syn::Error::new(
+                    Span::call_site(),
+                    "this macro needs at least one block",
+                ));
+            }
+            _ => {}
+        }
         let num = blocks.len();
-        trys::desugar_trys(&mut blocks);
-
-        let output_type = format_ident!("{}_OutputEnum", private_ident);
-        let mut replacer = BreakReplacer {
-            output_type: &output_type,
-            labels: Vec::new(),
-            loop_level: 0,
-            found: HashMap::new(),
-            private_ident: &private_ident,
-        };
-        blocks.iter_mut().for_each(|block| {
-            use syn::visit_mut::VisitMut;
-            replacer.visit_expr_block_mut(block);
-        });
-
-        let all_breaks = replacer
-            .found
+        // The `on_cancel` code is transformed along with the blocks, as if it were more blocks,
+        // so that it can use the shared captures.
+        let on_cancel_of = on_cancels
             .iter()
-            .filter_map(|(escape, (ident, has_expr))| match escape {
-                Escape::Break(_label) => Some((ident.to_owned(), has_expr)),
-                _ => None,
-            })
+            .enumerate()
+            .filter_map(|(idx, on_cancel)| on_cancel.as_ref().map(|_| idx))
             .collect::<Vec<_>>();
-
-        let br_variants_with_expr = all_breaks
-            .iter()
-            .filter_map(|(ident, has_expr)| if **has_expr { Some(ident) } else { None })
-            .collect::<Vec<_>>();
-        let br_variants_without_expr = all_breaks
-            .iter()
-            .filter_map(|(ident, has_expr)| if **has_expr { None } else { Some(ident) })
-            .collect::<Vec<_>>();
-
-        let co_variants = replacer
-            .found
-            .iter()
-            .filter_map(|(escape, (ident, _))| match escape {
-                Escape::Continue(_label) => Some(ident),
-                _ => None,
-            })
-            .collect::<Vec<_>>();
-
-        let re_variants = replacer
-            .found
-            .iter()
-            .filter_map(|(escape, (ident, _))| match escape {
-                Escape::Return => Some(ident),
-                _ => None,
-            })
-            .collect::<Vec<_>>();
-
-        let convert_breaking_ty = format_ident!("{}_TargetType", private_ident);
+        blocks.extend(on_cancels.into_iter().flatten());
+        let Transformed {
+            borrows,
+            return_type,
+            escape_arms,
+        } = transform_blocks(
+            &mut blocks,
+            &on_cancel_of,
+            make_borrows,
+            false,
+            &options,
+            &private_ident,
+        )?;
+        let on_cancel_blocks = blocks.split_off(num);
+        let output_type = format_ident!("{}_OutputEnum", private_ident);
         let keep_ty = format_ident!("{}_Keep", private_ident);
-        let return_type = quote!(
-            enum #output_type <#(#br_variants_with_expr,)* #(#re_variants,)* #keep_ty> {
-                #keep_ty (#keep_ty),
-                #(#re_variants (#re_variants),)*
-                #(#br_variants_with_expr (#br_variants_with_expr),)*
-                #(#br_variants_without_expr (()) ,)*
-                #(#co_variants (()),)*
-            }
-            impl <#(#br_variants_with_expr,)* #(#re_variants,)* #keep_ty> #output_type <#(#br_variants_with_expr,)* #(#re_variants,)* #keep_ty> {
-                fn convert_breaking<#convert_breaking_ty>(self) -> ::core::ops::ControlFlow<#output_type <#(#br_variants_with_expr,)* #(#re_variants,)* #convert_breaking_ty>, #keep_ty> {
-                    match self {
-                        Self :: #keep_ty (e) => ::core::ops::ControlFlow::Continue (e),
-                        #(Self :: #re_variants (e) => ::core::ops::ControlFlow::Break (#output_type :: #re_variants (e) ),)*
-                        #(Self :: #br_variants_with_expr (e) => ::core::ops::ControlFlow::Break (#output_type :: #br_variants_with_expr (e) ),)*
-                        #(Self :: #br_variants_without_expr (_) => ::core::ops::ControlFlow::Break (#output_type :: #br_variants_without_expr (()) ),)*
-                        #(Self :: #co_variants (_) => ::core::ops::ControlFlow::Break (#output_type :: #co_variants (())) ,)*
-                    }
-                }
-            }
-        );
-
-        let br_labels_with_expr =
-            replacer
-                .found
-                .iter()
-                .filter_map(|(escape, (_, has_expr))| match escape {
-                    Escape::Break(label) if *has_expr => Some(label),
-                    _ => None,
-                });
-        let br_labels_without_expr =
-            replacer
-                .found
-                .iter()
-                .filter_map(|(escape, (_, has_expr))| match escape {
-                    Escape::Break(label) if !*has_expr => Some(label),
-                    _ => None,
-                });
-
-        let co_labels = replacer
-            .found
-            .iter()
-            .filter_map(|(escape, _ident)| match escape {
-                Escape::Continue(label) => Some(label),
-                _ => None,
-            });
-
-        if borrows.is_some() {
-            awaits::replace_awaits(&mut blocks, &borrows_tuple, &borrows_cell);
-        }
 
         let poll_cx = format_ident!("{}_poll_cx", private_ident);
         let pinned_futs = format_ident!("{}_pinned_futs", private_ident);
         let indices = (0..num).map(syn::Index::from).collect::<Vec<_>>();
         let num_left = format_ident!("{}_num_left", private_ident);
         let outputs = format_ident!("{}_ouputs", private_ident);
-        let poller = quote! (
-            ::core::future::poll_fn(|#poll_cx| {
-                #(
-                    if ::core::option::Option::is_none(& #outputs . #indices) {
-                        match ::core::future::Future::poll(::core::pin::Pin::as_mut(&mut #pinned_futs . #indices), #poll_cx) {
-                            ::core::task::Poll::Ready (r) => match #output_type :: convert_breaking (r) {
-                                ::core::ops::ControlFlow::Continue (v) => {
-                                    #num_left -= 1;
-                                    #outputs . #indices = ::core::option::Option::Some(v)
-                                },
-                                ::core::ops::ControlFlow::Break (b) => return ::core::task::Poll::Ready (b),
+        let selected_type = format_ident!("{}_Selected", private_ident);
+        let else_variant = format_ident!("{}_Else", private_ident);
+        let branch_variants = (0..num)
+            .map(|idx| format_ident!("{}_Branch{}", private_ident, idx))
+            .collect::<Vec<_>>();
+        let disabled = format_ident!("{}_disabled", private_ident);
+        let named_type = format_ident!("{}_Named", private_ident);
+        // With named blocks, the output is a struct with a field for each block.
+        let named_struct = names.as_ref().map(|names| {
+            let generics = (0..num)
+                .map(|idx| format_ident!("{}_Field{}", private_ident, idx))
+                .collect::<Vec<_>>();
+            quote!(
+                #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+                struct #named_type <#(#generics,)*> {
+                    #(#names: #generics,)*
+                }
+            )
+        });
+        let wakers = format_ident!("{}_wakers", private_ident);
+        let on_cancel_guards = on_cancel_of
+            .iter()
+            .map(|idx| format_ident!("{}_on_cancel{}", private_ident, idx))
+            .collect::<Vec<_>>();
+        let running = format_ident!("{}_running", private_ident);
+        let stopping = format_ident!("{}_stopping", private_ident);
+        let extra_polls = format_ident!("{}_extra_polls", private_ident);
+        let graceful = options.graceful.as_ref();
+        // What to note down when a block finishes, whichever way it does.
+        // Marked before each poll, so that `on_cancel` knows the block has started.
+        let started = |index: &syn::Index| {
+            on_cancel_of
+                .iter()
+                .position(|idx| *idx == index.index as usize)
+                .map(|pos| {
+                    let guard = &on_cancel_guards[pos];
+                    quote!(::enjoin::__private::OnCancel::set_running(&mut #guard, true);)
+                })
+        };
+        let finished = |index: &syn::Index| {
+            // The block's `on_cancel` code runs if it is dropped while running.
+            let on_cancel = on_cancel_of
+                .iter()
+                .position(|idx| *idx == index.index as usize)
+                .map(|pos| {
+                    let guard = &on_cancel_guards[pos];
+                    quote!(::enjoin::__private::OnCancel::set_running(&mut #guard, false);)
+                });
+            let running = graceful.map(|_| quote!(#running [#index] = false;));
+            quote!(#on_cancel #running)
+        };
+        let poll_branches = indices.iter().zip(&arms).zip(&branch_variants).map(|((index, arm), variant)| {
+            let on_output = match mode {
+                Mode::Race if has_arms => match arm {
+                    // The value stays where it is if it doesn't match the pattern,
+                    // so only check against a reference to it.
+                    Some(Arm { pat, .. }) => {
+                        let pat = arms::pattern_for_check(pat);
+                        quote!(
+                            #[allow(unused_variables, unreachable_patterns)]
+                            let matched = match &v {
+                                #pat => true,
+                                _ => false,
+                            };
+                            if matched {
+                                return ::core::task::Poll::Ready (#output_type :: #keep_ty (#selected_type :: #variant (v)));
+                            }
+                            #disabled [#index] = true;
+                        )
+                    }
+                    None => quote!(
+                        return ::core::task::Poll::Ready (#output_type :: #keep_ty (#selected_type :: #variant (v)));
+                    ),
+                },
+                Mode::Join => quote!(
+                    #num_left -= 1;
+                    #outputs . #index = ::core::option::Option::Some(v);
+                ),
+                Mode::Race => quote!(
+                    return ::core::task::Poll::Ready (#output_type :: #keep_ty (v));
+                ),
+                // This is the value the block evaluated to.
+                // `?` inside the block was already desugared to return from the function.
+                Mode::TryJoin => quote!(
+                    match ::enjoin::polyfill::Try::branch(v) {
+                        ::core::ops::ControlFlow::Continue (o) => {
+                            #num_left -= 1;
+                            #outputs . #index = ::core::option::Option::Some(o);
+                        },
+                        ::core::ops::ControlFlow::Break (r) => return ::core::task::Poll::Ready (
+                            #output_type :: #keep_ty (::core::ops::ControlFlow::Break (r))
+                        ),
+                    }
+                ),
+                Mode::TryRace => quote!(
+                    match ::enjoin::polyfill::Try::branch(v) {
+                        ::core::ops::ControlFlow::Continue (o) => return ::core::task::Poll::Ready (
+                            #output_type :: #keep_ty (::core::result::Result::Ok (o))
+                        ),
+                        ::core::ops::ControlFlow::Break (r) => {
+                            #num_left -= 1;
+                            #outputs . #index = ::core::option::Option::Some(r);
+                        },
+                    }
+                ),
+            };
+            let branch_cx = if options.branch_wakers {
+                quote!(&mut ::core::task::Context::from_waker(
+                    ::enjoin::__private::BranchWakers::waker(&#wakers, #index)
+                ))
+            } else {
+                quote!(#poll_cx)
+            };
+            let start_running = started(index);
+            let stop_running = finished(index);
+            let poll = quote!(
+                #start_running
+                match ::core::future::Future::poll(::core::pin::Pin::as_mut(&mut #pinned_futs . #index), #branch_cx) {
+                    ::core::task::Poll::Ready (r) => {
+                        #stop_running
+                        match #output_type :: convert_breaking (r) {
+                            ::core::ops::ControlFlow::Continue (v) => {
+                                #on_output
                             },
-                            ::core::task::Poll::Pending => {}
+                            ::core::ops::ControlFlow::Break (b) => return ::core::task::Poll::Ready (b),
                         }
+                    },
+                    ::core::task::Poll::Pending => {}
+                }
+            );
+            let mut conditions = Vec::new();
+            match mode {
+                // A block whose value didn't match its pattern is disabled.
+                Mode::Race if arm.is_some() => conditions.push(quote!(!#disabled [#index])),
+                // A race finishes as soon as any block finishes,
+                // so every block is still running here.
+                Mode::Race => {}
+                Mode::Join | Mode::TryJoin | Mode::TryRace => {
+                    conditions.push(quote!(::core::option::Option::is_none(& #outputs . #index)))
+                }
+            }
+            // This must come last, so that the woken flag is only cleared if we do poll.
+            if options.branch_wakers {
+                conditions.push(quote!(::enjoin::__private::BranchWakers::take_woken(&#wakers, #index)));
+            }
+            if conditions.is_empty() {
+                poll
+            } else {
+                quote!(
+                    if #(#conditions)&&* {
+                        #poll
                     }
-                )*
-                if #num_left == 0 {
-                    ::core::task::Poll::Ready (
-                        #output_type :: #keep_ty (
-                            (#(::core::option::Option::unwrap(::core::option::Option::take(&mut #outputs . #indices)),)*)
-                        )
-                    )
+                )
+            }
+        });
+        let all_polled = match mode {
+            Mode::Join | Mode::TryJoin | Mode::TryRace => {
+                let taken = indices.iter().map(|index| {
+                    quote!(::core::option::Option::unwrap(::core::option::Option::take(&mut #outputs . #index)))
+                });
+                let all_outputs = match &names {
+                    Some(names) => quote!(#named_type { #(#names: #taken,)* }),
+                    None => quote!((#(#taken,)*)),
+                };
+                let all_outputs = match mode {
+                    Mode::TryJoin => quote!(::core::ops::ControlFlow::Continue (#all_outputs)),
+                    Mode::TryRace => quote!(::core::result::Result::Err (#all_outputs)),
+                    _ => all_outputs,
+                };
+                quote!(
+                    if #num_left == 0 {
+                        ::core::task::Poll::Ready (#output_type :: #keep_ty (#all_outputs))
+                    }
+                    else {
+                        ::core::task::Poll::Pending
+                    }
+                )
+            }
+            Mode::Race if arms.iter().all(Option::is_some) => quote!(
+                if #disabled == [true; #num] {
+                    ::core::task::Poll::Ready (#output_type :: #keep_ty (#selected_type :: #else_variant))
                 }
                 else {
                     ::core::task::Poll::Pending
                 }
-            })
+            ),
+            Mode::Race => quote!(::core::task::Poll::Pending),
+        };
+        let register_waker = options.branch_wakers.then(|| {
+            quote!(::enjoin::__private::BranchWakers::register(&#wakers, ::core::task::Context::waker(#poll_cx));)
+        });
+        let next_start = format_ident!("{}_next_start", private_ident);
+        // Rotating only makes a difference with more than one block.
+        let rotate = options.poll_order == PollOrder::Rotate && num > 1;
+        let poll_all_branches = match rotate {
+            false => quote!(#(#poll_branches)*),
+            true => quote!(
+                let start = #next_start;
+                #next_start = (start + 1) % #num;
+                for offset in 0..#num {
+                    match (start + offset) % #num {
+                        #(#indices => { #poll_branches })*
+                        _ => ::core::unreachable!(),
+                    }
+                }
+            ),
+        };
+        let poller = match graceful {
+            None => quote! (
+                ::core::future::poll_fn(|#poll_cx| {
+                    #register_waker
+                    #poll_all_branches
+                    #all_polled
+                })
+            ),
+            // Instead of stopping with blocks still running, keep the result,
+            // tell the blocks, and poll them some more.
+            Some(Graceful { signal, .. }) => {
+                let wind_down = indices.iter().map(|index| {
+                    let started = started(index);
+                    let finished = finished(index);
+                    // Outputs that come in now still count for `on_escape`.
+                    let keep = match mode {
+                        Mode::Join => quote!(
+                            if let ::core::ops::ControlFlow::Continue (v) = #output_type :: convert_breaking::<()> (r) {
+                                #outputs . #index = ::core::option::Option::Some(v);
+                            }
+                        ),
+                        Mode::TryJoin => quote!(
+                            if let ::core::ops::ControlFlow::Continue (v) = #output_type :: convert_breaking::<()> (r) {
+                                if let ::core::ops::ControlFlow::Continue (o) = ::enjoin::polyfill::Try::branch(v) {
+                                    #outputs . #index = ::core::option::Option::Some(o);
+                                }
+                            }
+                        ),
+                        Mode::Race | Mode::TryRace => quote!(let _ = r;),
+                    };
+                    quote!(
+                        if #running [#index] {
+                            #started
+                            if let ::core::task::Poll::Ready (r) = ::core::future::Future::poll(::core::pin::Pin::as_mut(&mut #pinned_futs . #index), #poll_cx) {
+                                #finished
+                                #keep
+                            }
+                        }
+                    )
+                });
+                quote! (
+                    ::core::future::poll_fn(|#poll_cx| {
+                        if ::core::option::Option::is_some(&#stopping) {
+                            #(#wind_down)*
+                            #extra_polls -= 1;
+                            return if #extra_polls == 0 || #running == [false; #num] {
+                                ::core::task::Poll::Ready (::core::option::Option::unwrap(::core::option::Option::take(&mut #stopping)))
+                            } else {
+                                // The blocks might not wake us, so come back for the next extra poll.
+                                ::core::task::Waker::wake_by_ref(::core::task::Context::waker(#poll_cx));
+                                ::core::task::Poll::Pending
+                            };
+                        }
+                        #[allow(clippy::redundant_closure_call)]
+                        let poll = (|| {
+                            #register_waker
+                            #poll_all_branches
+                            #all_polled
+                        })();
+                        match poll {
+                            ::core::task::Poll::Ready (r) if #extra_polls > 0 && #running != [false; #num] => {
+                                ::enjoin::__private::cancel(&#signal);
+                                #stopping = ::core::option::Option::Some(r);
+                                ::core::task::Waker::wake_by_ref(::core::task::Context::waker(#poll_cx));
+                                ::core::task::Poll::Pending
+                            }
+                            poll => poll,
+                        }
+                    })
+                )
+            }
+        };
+        let state = match mode {
+            Mode::Join | Mode::TryJoin | Mode::TryRace => {
+                let none: syn::Path = parse_quote!(::core::option::Option::None);
+                let nones = (0..num).map(|_| &none);
+                quote!(
+                    let mut #num_left = #num;
+                    let mut #outputs = (#(#nones,)*);
+                )
+            }
+            Mode::Race if arms.iter().any(Option::is_some) => quote!(
+                let mut #disabled = [false; #num];
+            ),
+            Mode::Race => quote!(),
+        };
+        let wakers_state = options
+            .branch_wakers
+            .then(|| quote!(let #wakers = ::enjoin::__private::BranchWakers::new(#num);));
+        let order_state = rotate.then(|| quote!(let mut #next_start: usize = 0;));
+        let try_output = format_ident!("{}_try_output", private_ident);
+        let (output_helper, output) = match mode {
+            Mode::Join | Mode::Race | Mode::TryRace => (quote!(), quote!(e)),
+            Mode::TryJoin => (
+                quote!(
+                    fn #try_output<R: ::enjoin::polyfill::Residual<O>, O>(
+                        flow: ::core::ops::ControlFlow<R, O>,
+                    ) -> <R as ::enjoin::polyfill::Residual<O>>::TryType {
+                        match flow {
+                            ::core::ops::ControlFlow::Continue (o) => ::enjoin::polyfill::Try::from_output(o),
+                            ::core::ops::ControlFlow::Break (r) => ::enjoin::polyfill::FromResidual::from_residual(r),
+                        }
+                    }
+                ),
+                quote!(#try_output(e)),
+            ),
+        };
+        let graceful_state = graceful.map(
+            |Graceful {
+                 signal,
+                 extra_polls: n,
+             }| {
+                quote!(
+                    let #signal = ::enjoin::__private::new_cancelled();
+                    let mut #running = [true; #num];
+                    let mut #stopping = ::core::option::Option::None;
+                    let mut #extra_polls: usize = #n;
+                )
+            },
         );
-        let none: syn::Path = parse_quote!(::core::option::Option::None);
-        let nones = repeat(&none).take(num);
+        let setup = quote!(
+            #graceful_state
+            #(let mut #on_cancel_guards = ::enjoin::__private::OnCancel::new(|| #on_cancel_blocks);)*
+            #[allow(clippy::await_holding_refcell_ref)]
+            let mut #pinned_futs = (
+                #(::core::pin::pin!(async {
+                    #[allow(unreachable_code)]
+                    #output_type :: #keep_ty (
+                        #[warn(unreachable_code)]
+                        #blocks
+                    )
+                }),)*
+            );
+            #state
+            #wakers_state
+            #order_state
+        );
+        let timeout_variant = format_ident!("{}_Timeout", private_ident);
+        // Poll the timer after the blocks, so that a block finishing at the same time wins.
+        let await_blocks = match &timeout {
+            None => quote!(#poller .await),
+            Some(Timeout {
+                duration, timer, ..
+            }) => {
+                let timer_value = format_ident!("{}_timer", private_ident);
+                let sleep = format_ident!("{}_sleep", private_ident);
+                let poll_blocks = format_ident!("{}_poll_blocks", private_ident);
+                quote!({
+                    let #timer_value = #timer;
+                    let mut #sleep = ::core::pin::pin!(::enjoin::Timer::sleep(&#timer_value, #duration));
+                    let mut #poll_blocks = #poller;
+                    ::core::future::poll_fn(|#poll_cx| {
+                        if let ::core::task::Poll::Ready (r) = ::core::future::Future::poll(::core::pin::Pin::new(&mut #poll_blocks), #poll_cx) {
+                            return ::core::task::Poll::Ready (::core::option::Option::Some (r));
+                        }
+                        ::core::task::Poll::map(
+                            ::core::future::Future::poll(::core::pin::Pin::as_mut(&mut #sleep), #poll_cx),
+                            |()| ::core::option::Option::None,
+                        )
+                    }).await
+                })
+            }
+        };
+        let run = if options.on_escape.is_none() && timeout.is_none() {
+            quote!(
+                #setup
+                match #await_blocks {
+                    #output_type :: #keep_ty (e) => #output,
+                    #escape_arms
+                }
+            )
+        } else {
+            // The blocks are dropped at the end of the inner block,
+            // so the handlers can use what they borrowed.
+            let result = format_ident!("{}_result", private_ident);
+            let partial = format_ident!("{}_partial", private_ident);
+            let (result_pat, partial_value) = match &options.on_escape {
+                Some(_) => (quote!((#result, #partial)), quote!(, #outputs)),
+                None => (quote!(#result), quote!()),
+            };
+            let call_on_escape = options
+                .on_escape
+                .as_ref()
+                .map(|on_escape| quote!(::enjoin::__private::on_escape(#on_escape, #partial);));
+            let finished = |pat: TokenStream| match &timeout {
+                Some(_) => quote!(::core::option::Option::Some (#pat)),
+                None => pat,
+            };
+            let keep = finished(quote!(#output_type :: #keep_ty (e)));
+            let escaped = finished(quote!(escaped));
+            let timed_out = timeout.as_ref().map(|Timeout { handler, .. }| {
+                // With arms, the handlers are matched later, along with the others.
+                let handler = match has_arms {
+                    true => quote!(#selected_type :: #timeout_variant),
+                    false => quote!(#handler),
+                };
+                quote!(::core::option::Option::None => #handler,)
+            });
+            quote!(
+                let #result_pat = {
+                    #setup
+                    (#await_blocks #partial_value)
+                };
+                match #result {
+                    #keep => #output,
+                    #timed_out
+                    #[allow(unreachable_patterns)]
+                    #escaped => {
+                        #call_on_escape
+                        match escaped {
+                            #escape_arms
+                            #[allow(unreachable_patterns)]
+                            _ => ::core::unreachable!(),
+                        }
+                    }
+                }
+            )
+        };
+        if !has_arms {
+            return Ok(quote! {
+                {
+                    #borrows
+                    #return_type
+                    #named_struct
+                    #output_helper
+                    #run
+                }
+            });
+        }
+
+        // The handlers run after all the blocks have been dropped,
+        // outside of any async block. This way, they can borrow what the blocks borrowed,
+        // and branching statements in them just work.
+        let selected = format_ident!("{}_selected", private_ident);
+        let handlers = arms
+            .iter()
+            .zip(&branch_variants)
+            .map(|(arm, variant)| match arm {
+                Some(Arm { pat, handler }) => {
+                    quote!(#selected_type :: #variant (#pat) => #handler,)
+                }
+                None => quote!(#selected_type :: #variant (v) => v,),
+            });
+        let else_handler = match else_handler {
+            Some((_, handler)) => quote!(#handler),
+            None => quote!(::core::panic!(
+                "all branches are disabled and there is no else branch"
+            )),
+        };
+        let timeout_decl = timeout.as_ref().map(|_| quote!(#timeout_variant,));
+        let timeout_handler = timeout
+            .as_ref()
+            .map(|Timeout { handler, .. }| quote!(#selected_type :: #timeout_variant => #handler,));
         Ok(quote! {
             {
                 #borrows
                 #return_type
-                let mut #pinned_futs = (
-                    #(::core::pin::pin!(async {
-                        #[allow(unreachable_code)]
-                        #output_type :: #keep_ty (
-                            #[warn(unreachable_code)]
-                            #blocks
-                        )
-                    }),)*
-                );
-                let mut #num_left = #num;
-                let mut #outputs = (#(#nones,)*);
-                match #poller .await {
-                    #output_type :: #keep_ty (e) => e,
-                    #(#output_type :: #re_variants (e) => return e,)*
-                    #(#output_type :: #br_variants_with_expr (e) => break #br_labels_with_expr e,)*
-                    #(#output_type :: #br_variants_without_expr (_) => break #br_labels_without_expr,)*
-                    #(#output_type :: #co_variants (_) => continue #co_labels,)*
+                enum #selected_type <#(#branch_variants,)*> {
+                    #(#branch_variants (#branch_variants),)*
+                    #else_variant,
+                    #timeout_decl
+                }
+                let #selected = {
+                    #run
+                };
+                match #selected {
+                    #(#handlers)*
+                    #selected_type :: #else_variant => #else_handler,
+                    #timeout_handler
+                    #[allow(unreachable_patterns)]
+                    _ => ::core::unreachable!(),
                 }
             }
         })
     }
 }
+
+struct Transformed {
+    /// Code for creating the cell that holds shared borrows, if needed.
+    borrows: Option<TokenStream>,
+    /// The output enum and its `convert_breaking` method.
+    return_type: TokenStream,
+    /// Match arms that perform the escapes carried by the output enum.
+    escape_arms: TokenStream,
+}
+
+/// Run all the code transformation passes on the blocks.
+/// If `repeated`, each block is run more than once concurrently,
+/// so all its mutable captures are shared.
+/// The last `on_cancel_of.len()` blocks are the `on_cancel` code
+/// of the blocks at those indices.
+fn transform_blocks(
+    blocks: &mut [ExprBlock],
+    on_cancel_of: &[usize],
+    make_borrows: bool,
+    repeated: bool,
+    options: &Options,
+    private_ident: &Ident,
+) -> syn::Result<Transformed> {
+    let borrows_guard = format_ident!("{}_borrows", private_ident);
+    let borrows_cell = format_ident!("{}_borrows_cell", private_ident);
+    let copy_cells = format_ident!("{}_copy_cell", private_ident);
+    let nested = nested_macros::expose(blocks);
+    let borrows = if make_borrows {
+        captures::replace_captures_and_generate_borrows(
+            blocks,
+            on_cancel_of,
+            repeated,
+            options,
+            &borrows_guard,
+            &borrows_cell,
+            &copy_cells,
+        )?
+    } else if let Some(copy) = options.copy.first() {
+        return Err(syn::Error::new(
+            copy.span(),
+            "`copy(...)` is only supported in the `_auto_borrow` macros",
+        ));
+    } else if let Some(share) = options.share.as_ref().and_then(|share| share.first()) {
+        return Err(syn::Error::new(
+            share.place.span(),
+            "`share(...)` is only supported in the `_auto_borrow` macros",
+        ));
+    } else if let Some(method) = options.ref_methods.first() {
+        return Err(syn::Error::new(
+            method.span(),
+            "`ref_methods(...)` is only supported in the `_auto_borrow` macros",
+        ));
+    } else if let Some(explain) = &options.explain {
+        return Err(syn::Error::new(
+            explain.span(),
+            "`explain` is only supported in the `_auto_borrow` macros",
+        ));
+    } else {
+        None
+    };
+
+    trys::desugar_trys(blocks);
+
+    let output_type = format_ident!("{}_OutputEnum", private_ident);
+    let mut replacer = BreakReplacer {
+        output_type: &output_type,
+        labels: Vec::new(),
+        loop_level: 0,
+        found: HashMap::new(),
+        private_ident,
+    };
+    blocks.iter_mut().for_each(|block| {
+        use syn::visit_mut::VisitMut;
+        replacer.visit_expr_block_mut(block);
+    });
+
+    let all_breaks = replacer
+        .found
+        .iter()
+        .filter_map(|(escape, (ident, has_expr))| match escape {
+            Escape::Break(_label) => Some((ident.to_owned(), has_expr)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let br_variants_with_expr = all_breaks
+        .iter()
+        .filter_map(|(ident, has_expr)| if **has_expr { Some(ident) } else { None })
+        .collect::<Vec<_>>();
+    let br_variants_without_expr = all_breaks
+        .iter()
+        .filter_map(|(ident, has_expr)| if **has_expr { None } else { Some(ident) })
+        .collect::<Vec<_>>();
+
+    let co_variants = replacer
+        .found
+        .iter()
+        .filter_map(|(escape, (ident, _))| match escape {
+            Escape::Continue(_label) => Some(ident),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let re_variants = replacer
+        .found
+        .iter()
+        .filter_map(|(escape, (ident, _))| match escape {
+            Escape::Return => Some(ident),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let convert_breaking_ty = format_ident!("{}_TargetType", private_ident);
+    let keep_ty = format_ident!("{}_Keep", private_ident);
+    let return_type = quote!(
+        enum #output_type <#(#br_variants_with_expr,)* #(#re_variants,)* #keep_ty> {
+            #keep_ty (#keep_ty),
+            #(#re_variants (#re_variants),)*
+            #(#br_variants_with_expr (#br_variants_with_expr),)*
+            #(#br_variants_without_expr (()) ,)*
+            #(#co_variants (()),)*
+        }
+        impl <#(#br_variants_with_expr,)* #(#re_variants,)* #keep_ty> #output_type <#(#br_variants_with_expr,)* #(#re_variants,)* #keep_ty> {
+            fn convert_breaking<#convert_breaking_ty>(self) -> ::core::ops::ControlFlow<#output_type <#(#br_variants_with_expr,)* #(#re_variants,)* #convert_breaking_ty>, #keep_ty> {
+                match self {
+                    Self :: #keep_ty (e) => ::core::ops::ControlFlow::Continue (e),
+                    #(Self :: #re_variants (e) => ::core::ops::ControlFlow::Break (#output_type :: #re_variants (e) ),)*
+                    #(Self :: #br_variants_with_expr (e) => ::core::ops::ControlFlow::Break (#output_type :: #br_variants_with_expr (e) ),)*
+                    #(Self :: #br_variants_without_expr (_) => ::core::ops::ControlFlow::Break (#output_type :: #br_variants_without_expr (()) ),)*
+                    #(Self :: #co_variants (_) => ::core::ops::ControlFlow::Break (#output_type :: #co_variants (())) ,)*
+                }
+            }
+        }
+    );
+
+    let br_labels_with_expr =
+        replacer
+            .found
+            .iter()
+            .filter_map(|(escape, (_, has_expr))| match escape {
+                Escape::Break(label) if *has_expr => Some(label),
+                _ => None,
+            });
+    let br_labels_without_expr =
+        replacer
+            .found
+            .iter()
+            .filter_map(|(escape, (_, has_expr))| match escape {
+                Escape::Break(label) if !*has_expr => Some(label),
+                _ => None,
+            });
+
+    let co_labels = replacer.found.keys().filter_map(|escape| match escape {
+        Escape::Continue(label) => Some(label),
+        _ => None,
+    });
+
+    if let Some(borrows) = &borrows {
+        awaits::replace_awaits(blocks, &borrows.cells)?;
+    }
+    nested.restore(blocks);
+
+    let escape_arms = quote!(
+        #(#output_type :: #re_variants (e) => return e,)*
+        #(#output_type :: #br_variants_with_expr (e) => break #br_labels_with_expr e,)*
+        #(#output_type :: #br_variants_without_expr (_) => break #br_labels_without_expr,)*
+        #(#output_type :: #co_variants (_) => continue #co_labels,)*
+    );
+    Ok(Transformed {
+        borrows: borrows.map(|b| b.setup),
+        return_type,
+        escape_arms,
+    })
+}
@@ -0,0 +1,385 @@
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use quote::quote;
+use syn::{
+    parse_quote, punctuated::Punctuated, visit_mut::VisitMut, Attribute, Block, Expr, ExprArray,
+    ExprAssign, ExprAsync, ExprBlock, ExprMacro, Ident, Macro, Stmt, Token,
+};
+
+use crate::{for_each::ForInput, MacroInput};
+
+/// Marks the stand-in of a macro whose arguments are expressions evaluated in place,
+/// like `println!` or `vec!`.
+pub(crate) const INLINE: &str = "__enjoin_inline_macro";
+/// Marks the stand-in of a macro whose arguments are expressions evaluated in place,
+/// and which then awaits internally, like `tokio::join!`.
+pub(crate) const YIELDING: &str = "__enjoin_yielding_macro";
+/// Marks the stand-in of one of our own macros.
+/// Its blocks are stood in for by async blocks, since they run concurrently like async blocks.
+pub(crate) const CONCURRENT: &str = "__enjoin_concurrent_macro";
+
+/// Our macros, which are expanded by the compiler after we're done with the blocks
+/// they are nested in.
+const ENJOIN_MACROS: [&str; 7] = [
+    "join",
+    "join_auto_borrow",
+    "join_for",
+    "join_for_auto_borrow",
+    "race",
+    "try_join",
+    "try_race",
+];
+/// Macros that take comma-separated expressions and evaluate them in place.
+const INLINE_MACROS: [&str; 19] = [
+    "assert",
+    "assert_eq",
+    "assert_ne",
+    "dbg",
+    "debug_assert",
+    "debug_assert_eq",
+    "debug_assert_ne",
+    "eprint",
+    "eprintln",
+    "format",
+    "panic",
+    "print",
+    "println",
+    "todo",
+    "unimplemented",
+    "unreachable",
+    "vec",
+    "write",
+    "writeln",
+];
+/// Macros that await internally.
+const YIELDING_MACROS: [&str; 11] = [
+    "join",
+    "join_auto_borrow",
+    "join_for",
+    "join_for_auto_borrow",
+    "pending",
+    "race",
+    "select",
+    "select_biased",
+    "try_join",
+    "try_race",
+    "yield_now",
+];
+
+/// The known macros found in the blocks.
+pub(crate) struct NestedMacros {
+    macros: Vec<Nested>,
+}
+
+struct Nested {
+    attrs: Vec<Attribute>,
+    mac: Macro,
+    /// Only for our own macros.
+    pieces: Option<Vec<Piece>>,
+    /// The names of named format arguments, like `n` in `println!("{n}", n = 1)`,
+    /// which are not part of their stand-in elements.
+    arg_names: Vec<Option<Ident>>,
+}
+
+/// For our own macros, the input tokens with the blocks taken out.
+enum Piece {
+    Tokens(TokenStream),
+    Block,
+}
+
+/// Replace the known macros in the blocks with stand-in expressions
+/// made from their parsed input, so that the other passes can work inside them.
+pub(crate) fn expose(blocks: &mut [ExprBlock]) -> NestedMacros {
+    let mut exposer = Exposer {
+        found: NestedMacros { macros: Vec::new() },
+    };
+    blocks.iter_mut().for_each(|block| {
+        exposer.visit_expr_block_mut(block);
+    });
+    exposer.found
+}
+
+impl NestedMacros {
+    /// Turn the stand-ins back into macro calls.
+    pub fn restore(self, blocks: &mut [ExprBlock]) {
+        if self.macros.is_empty() {
+            return;
+        }
+        let mut restorer = Restorer {
+            macros: self.macros.into_iter().map(Some).collect(),
+        };
+        blocks.iter_mut().for_each(|block| {
+            restorer.visit_expr_block_mut(block);
+        });
+    }
+}
+
+/// Which kind of stand-in the expression is, if it is one.
+pub(crate) fn stand_in_kind(expr: &Expr) -> Option<&'static str> {
+    match expr {
+        Expr::Array(arr) => arr.attrs.iter().find_map(|attr| {
+            [INLINE, YIELDING, CONCURRENT]
+                .into_iter()
+                .find(|kind| attr.path().is_ident(kind))
+        }),
+        _ => None,
+    }
+}
+
+/// Whether a macro we couldn't look inside might await.
+pub(crate) fn might_await(mac: &Macro) -> bool {
+    fn has_await(tokens: TokenStream) -> bool {
+        let mut after_dot = false;
+        tokens.into_iter().any(|tt| match tt {
+            TokenTree::Ident(ident) if after_dot && ident == "await" => true,
+            TokenTree::Punct(p) => {
+                after_dot = p.as_char() == '.';
+                false
+            }
+            TokenTree::Group(g) => {
+                after_dot = false;
+                has_await(g.stream())
+            }
+            _ => {
+                after_dot = false;
+                false
+            }
+        })
+    }
+    mac.path
+        .segments
+        .last()
+        .is_some_and(|last| YIELDING_MACROS.iter().any(|name| last.ident == name))
+        || has_await(mac.tokens.clone())
+}
+
+struct Exposer {
+    found: NestedMacros,
+}
+
+impl Exposer {
+    fn stand_in(&mut self, attrs: &[Attribute], mac: &Macro) -> Option<Expr> {
+        let segments = mac
+            .path
+            .segments
+            .iter()
+            .map(|s| s.ident.to_string())
+            .collect::<Vec<_>>();
+        let name = segments.last()?.as_str();
+        let first = segments.first()?.as_str();
+        let ours = ENJOIN_MACROS.contains(&name)
+            && if segments.len() == 1 {
+                // `join!` and `try_join!` could also be the ones from `futures`.
+                !matches!(name, "join" | "try_join") || only_blocks(mac.tokens.clone())
+            } else {
+                first == "enjoin"
+            };
+        let (kind, elems, pieces) = if ours {
+            let (elems, pieces) = split_blocks(name, mac.tokens.clone())?;
+            (CONCURRENT, elems, Some(pieces))
+        } else {
+            let kind = if INLINE_MACROS.contains(&name)
+                && (segments.len() == 1 || matches!(first, "std" | "core" | "alloc"))
+            {
+                INLINE
+            } else if matches!(name, "join" | "try_join")
+                && (segments.len() == 1 || matches!(first, "tokio" | "futures"))
+            {
+                YIELDING
+            } else {
+                return None;
+            };
+            let elems = mac
+                .parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated)
+                .ok()?;
+            (kind, elems.into_iter().collect(), None)
+        };
+        // In formatting macros, `name = value` names an argument rather than assigning.
+        let formatting = kind == INLINE && !matches!(name, "vec" | "dbg");
+        let (arg_names, elems): (Vec<_>, Vec<_>) = elems
+            .into_iter()
+            .map(|elem| match elem {
+                Expr::Assign(assign) if formatting => match *assign.left {
+                    Expr::Path(path)
+                        if path.attrs.is_empty() && path.path.get_ident().is_some() =>
+                    {
+                        (path.path.get_ident().cloned(), *assign.right)
+                    }
+                    left => (
+                        None,
+                        Expr::Assign(ExprAssign {
+                            left: Box::new(left),
+                            ..assign
+                        }),
+                    ),
+                },
+                elem => (None, elem),
+            })
+            .unzip();
+        let index = self.found.macros.len();
+        self.found.macros.push(Nested {
+            attrs: attrs.to_owned(),
+            mac: mac.to_owned(),
+            pieces,
+            arg_names,
+        });
+        let marker = Ident::new(kind, proc_macro2::Span::call_site());
+        Some(Expr::Array(ExprArray {
+            attrs: vec![parse_quote!(#[#marker(#index)])],
+            bracket_token: Default::default(),
+            elems: elems.into_iter().collect(),
+        }))
+    }
+}
+
+impl VisitMut for Exposer {
+    fn visit_item_mut(&mut self, _i: &mut syn::Item) {}
+
+    fn visit_block_mut(&mut self, i: &mut Block) {
+        let num_stmts = i.stmts.len();
+        for (idx, stmt) in i.stmts.iter_mut().enumerate() {
+            if let Stmt::Macro(sm) = stmt {
+                if let Some(expr) = self.stand_in(&sm.attrs, &sm.mac) {
+                    // The stand-in might get wrapped, so it needs a semicolon unless it's last.
+                    let semi = sm
+                        .semi_token
+                        .or((idx + 1 != num_stmts).then(Default::default));
+                    *stmt = Stmt::Expr(expr, semi);
+                } else if might_await(&sm.mac) {
+                    let semi = sm
+                        .semi_token
+                        .or((idx + 1 != num_stmts).then(Default::default));
+                    *stmt = Stmt::Expr(
+                        Expr::Macro(ExprMacro {
+                            attrs: std::mem::take(&mut sm.attrs),
+                            mac: sm.mac.to_owned(),
+                        }),
+                        semi,
+                    );
+                }
+            }
+            self.visit_stmt_mut(stmt);
+        }
+    }
+
+    fn visit_expr_mut(&mut self, i: &mut Expr) {
+        if let Expr::Macro(em) = i {
+            if let Some(expr) = self.stand_in(&em.attrs, &em.mac) {
+                *i = expr;
+            }
+        }
+        syn::visit_mut::visit_expr_mut(self, i);
+    }
+}
+
+/// Whether the tokens are comma-separated (possibly labeled) blocks,
+/// as in `join!({ ... }, { ... })`.
+fn only_blocks(tokens: TokenStream) -> bool {
+    let mut in_segment = Vec::new();
+    let mut segments = Vec::new();
+    for tt in tokens {
+        match &tt {
+            TokenTree::Punct(p) if p.as_char() == ',' => {
+                segments.push(std::mem::take(&mut in_segment))
+            }
+            _ => in_segment.push(tt),
+        }
+    }
+    segments.push(in_segment);
+    segments.iter().all(|segment| match segment.as_slice() {
+        [] => true,
+        [.., TokenTree::Group(g)] => g.delimiter() == Delimiter::Brace,
+        _ => false,
+    }) && segments.iter().any(|segment| !segment.is_empty())
+}
+
+/// Take the blocks out of the input of one of our macros.
+/// Our own parser finds them, so that other braces, like handlers of race arms,
+/// `on_cancel` code, or closures in the options, are left alone,
+/// since they don't run concurrently.
+fn split_blocks(name: &str, tokens: TokenStream) -> Option<(Vec<Expr>, Vec<Piece>)> {
+    let block_ends = if name.starts_with("join_for") {
+        vec![syn::parse2::<ForInput>(tokens.clone()).ok()?.body_end]
+    } else {
+        syn::parse2::<MacroInput>(tokens.clone()).ok()?.block_ends
+    };
+    let num_tokens = tokens.clone().into_iter().count();
+    let block_positions = block_ends
+        .iter()
+        .map(|left| num_tokens - left - 1)
+        .collect::<Vec<_>>();
+    let mut elems = Vec::new();
+    let mut pieces = Vec::new();
+    let mut current = TokenStream::new();
+    for (position, tt) in tokens.into_iter().enumerate() {
+        if block_positions.contains(&position) {
+            let block: Block = syn::parse2(TokenStream::from(tt)).ok()?;
+            elems.push(Expr::Async(ExprAsync {
+                attrs: Vec::new(),
+                async_token: Default::default(),
+                capture: None,
+                block,
+            }));
+            pieces.push(Piece::Tokens(std::mem::take(&mut current)));
+            pieces.push(Piece::Block);
+        } else {
+            current.extend([tt]);
+        }
+    }
+    pieces.push(Piece::Tokens(current));
+    Some((elems, pieces))
+}
+
+struct Restorer {
+    macros: Vec<Option<Nested>>,
+}
+
+impl VisitMut for Restorer {
+    fn visit_expr_mut(&mut self, i: &mut Expr) {
+        syn::visit_mut::visit_expr_mut(self, i);
+        if stand_in_kind(i).is_none() {
+            return;
+        }
+        let Expr::Array(arr) = i else {
+            return;
+        };
+        let index: usize = arr.attrs[0]
+            .parse_args::<syn::LitInt>()
+            .unwrap()
+            .base10_parse()
+            .unwrap();
+        let Nested {
+            attrs,
+            mut mac,
+            pieces,
+            arg_names,
+        } = self.macros[index].take().unwrap();
+        let elems = std::mem::take(&mut arr.elems);
+        mac.tokens = match pieces {
+            None => {
+                let elems = elems.iter().zip(arg_names).map(|(elem, name)| match name {
+                    Some(name) => quote!(#name = #elem),
+                    None => quote!(#elem),
+                });
+                quote!(#(#elems),*)
+            }
+            Some(pieces) => {
+                let mut blocks = elems.into_iter().map(|elem| match elem {
+                    Expr::Async(a) => a.block,
+                    _ => unreachable!(),
+                });
+                let mut tokens = TokenStream::new();
+                for piece in pieces {
+                    match piece {
+                        Piece::Tokens(t) => tokens.extend(t),
+                        Piece::Block => {
+                            let block = blocks.next().unwrap();
+                            tokens.extend(quote!(#block));
+                        }
+                    }
+                }
+                tokens
+            }
+        };
+        *i = Expr::Macro(ExprMacro { attrs, mac });
+    }
+}
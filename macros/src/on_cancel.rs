@@ -0,0 +1,116 @@
+use proc_macro2::Span;
+use syn::{parse::ParseStream, spanned::Spanned, visit::Visit, ExprBlock, Ident};
+
+/// Parse `on_cancel { ... }` after a block, if it is there.
+pub fn parse(input: ParseStream) -> syn::Result<Option<ExprBlock>> {
+    let is_on_cancel = input
+        .fork()
+        .parse::<Ident>()
+        .is_ok_and(|ident| ident == "on_cancel");
+    if !is_on_cancel {
+        return Ok(None);
+    }
+    input.parse::<Ident>()?;
+    let block: ExprBlock = input.parse()?;
+    check(&block)?;
+    Ok(Some(block))
+}
+
+/// The `on_cancel` code runs synchronously while the block is dropped,
+/// so it can't await, and there is nothing to jump out to.
+fn check(block: &ExprBlock) -> syn::Result<()> {
+    struct Checker {
+        labels: Vec<Ident>,
+        loop_level: usize,
+        error: Option<syn::Error>,
+    }
+    impl Checker {
+        fn error(&mut self, span: Span, what: &str) {
+            let e = syn::Error::new(
+                span,
+                format!("`on_cancel` code runs while the block is dropped, so it can't {what}"),
+            );
+            match &mut self.error {
+                Some(error) => error.combine(e),
+                None => self.error = Some(e),
+            }
+        }
+        fn in_loop(
+            &mut self,
+            label: Option<&syn::Label>,
+            is_loop: bool,
+            f: impl FnOnce(&mut Self),
+        ) {
+            self.loop_level += is_loop as usize;
+            if let Some(label) = label {
+                self.labels.push(label.name.ident.to_owned());
+            }
+            f(self);
+            if label.is_some() {
+                self.labels.pop();
+            }
+            self.loop_level -= is_loop as usize;
+        }
+    }
+    impl<'ast> Visit<'ast> for Checker {
+        fn visit_item(&mut self, _i: &'ast syn::Item) {}
+        fn visit_expr_async(&mut self, _i: &'ast syn::ExprAsync) {}
+        fn visit_expr_closure(&mut self, _i: &'ast syn::ExprClosure) {}
+
+        fn visit_expr_block(&mut self, i: &'ast ExprBlock) {
+            self.in_loop(i.label.as_ref(), false, |s| {
+                syn::visit::visit_expr_block(s, i)
+            });
+        }
+        fn visit_expr_for_loop(&mut self, i: &'ast syn::ExprForLoop) {
+            self.in_loop(i.label.as_ref(), true, |s| {
+                syn::visit::visit_expr_for_loop(s, i)
+            });
+        }
+        fn visit_expr_while(&mut self, i: &'ast syn::ExprWhile) {
+            self.in_loop(i.label.as_ref(), true, |s| {
+                syn::visit::visit_expr_while(s, i)
+            });
+        }
+        fn visit_expr_loop(&mut self, i: &'ast syn::ExprLoop) {
+            self.in_loop(i.label.as_ref(), true, |s| {
+                syn::visit::visit_expr_loop(s, i)
+            });
+        }
+
+        fn visit_expr_await(&mut self, i: &'ast syn::ExprAwait) {
+            self.error(i.await_token.span, "`.await`");
+            syn::visit::visit_expr_await(self, i);
+        }
+        fn visit_expr_try(&mut self, i: &'ast syn::ExprTry) {
+            self.error(i.question_token.span, "use `?`");
+            syn::visit::visit_expr_try(self, i);
+        }
+        fn visit_expr_return(&mut self, i: &'ast syn::ExprReturn) {
+            self.error(i.span(), "`return`");
+            syn::visit::visit_expr_return(self, i);
+        }
+        fn visit_expr_break(&mut self, i: &'ast syn::ExprBreak) {
+            match &i.label {
+                None if self.loop_level > 0 => {}
+                Some(l) if self.labels.contains(&l.ident) => {}
+                _ => self.error(i.span(), "`break` out of it"),
+            }
+            syn::visit::visit_expr_break(self, i);
+        }
+        fn visit_expr_continue(&mut self, i: &'ast syn::ExprContinue) {
+            match &i.label {
+                None if self.loop_level > 0 => {}
+                Some(l) if self.labels.contains(&l.ident) => {}
+                _ => self.error(i.span(), "`continue` out of it"),
+            }
+        }
+    }
+    let mut checker = Checker {
+        labels: Vec::new(),
+        loop_level: 0,
+        error: None,
+    };
+    checker.visit_block(&block.block);
+    checker.error.map_or(Ok(()), Err)
+}
@@ -0,0 +1,225 @@
+use syn::{
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    token, Expr, Ident, Token,
+};
+
+/// Options given at the start of the macro input, each followed by a `;`.
+#[derive(Default)]
+pub(crate) struct Options {
+    /// `branch_wakers;`: give each block its own waker,
+    /// and only poll the blocks that were woken.
+    pub branch_wakers: bool,
+    pub poll_order: PollOrder,
+    /// `limit = N;`: run at most `N` copies of the body at once.
+    /// Only for iterator joins.
+    pub limit: Option<Expr>,
+    /// `on_escape = |partial| { ... };`: called with the outputs of the blocks that
+    /// had already finished when a block escapes. Not for races.
+    pub on_escape: Option<Expr>,
+    /// `graceful(signal, N);`: when the macro stops with blocks still running,
+    /// tell them through `signal` and poll them up to `N` more times first.
+    pub graceful: Option<Graceful>,
+    /// `copy(a, b.field);`: captures to share through a `Cell`
+    /// instead of a `RefCell`. Only for the `_auto_borrow` macros.
+    pub copy: Vec<Expr>,
+    /// `share(mut a, b.field, move c);`: exactly which places the blocks share,
+    /// instead of guessing. Only for the `_auto_borrow` macros.
+    pub share: Option<Vec<ShareEntry>>,
+    /// `ref_methods(peek, name);`: methods that only borrow their receiver immutably,
+    /// on top of the known ones of std types. Only for the `_auto_borrow` macros.
+    pub ref_methods: Vec<Ident>,
+    /// `explain;`: warn at each capture with what was decided about it.
+    /// Only for the `_auto_borrow` macros.
+    pub explain: Option<Ident>,
+}
+
+/// The arguments of `graceful(signal, N)`.
+pub(crate) struct Graceful {
+    /// The name the blocks use for the `enjoin::Cancelled` signal.
+    pub signal: Ident,
+    /// How many more times the running blocks are polled.
+    pub extra_polls: Expr,
+}
+
+impl Parse for Graceful {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let signal = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let extra_polls = input.parse()?;
+        input.parse::<Option<Token![,]>>()?;
+        Ok(Self {
+            signal,
+            extra_polls,
+        })
+    }
+}
+
+/// One place listed in `share(...)`.
+pub(crate) struct ShareEntry {
+    pub kind: ShareKind,
+    pub place: Expr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ShareKind {
+    /// `mut place`: shared mutably, so it needs a cell.
+    Mut,
+    /// `place`: shared immutably.
+    Ref,
+    /// `move place`: moved into a block.
+    Move,
+}
+
+impl Parse for ShareEntry {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let kind = if input.peek(Token![mut]) {
+            input.parse::<Token![mut]>()?;
+            ShareKind::Mut
+        } else if input.peek(Token![move]) {
+            input.parse::<Token![move]>()?;
+            ShareKind::Move
+        } else {
+            ShareKind::Ref
+        };
+        Ok(Self {
+            kind,
+            place: input.parse()?,
+        })
+    }
+}
+
+/// The order in which blocks are polled each time the join is polled.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PollOrder {
+    /// `biased;`: always start from the first block.
+    #[default]
+    Biased,
+    /// `rotate;`: start one block further each time.
+    Rotate,
+}
+
+impl Options {
+    pub fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = Self::default();
+        let mut poll_order_set = false;
+        loop {
+            if input.peek(Ident) && input.peek2(Token![=]) {
+                // Could also be a race arm: `x = { block } => handler`.
+                let fork = input.fork();
+                let name: Ident = fork.parse()?;
+                fork.parse::<Token![=]>()?;
+                if fork.parse::<Expr>().is_err() || !fork.peek(Token![;]) {
+                    break;
+                }
+                input.parse::<Ident>()?;
+                input.parse::<Token![=]>()?;
+                let value: Expr = input.parse()?;
+                input.parse::<Token![;]>()?;
+                match name.to_string().as_str() {
+                    "limit" => {
+                        if options.limit.is_some() {
+                            return Err(syn::Error::new(name.span(), "`limit` is given twice"));
+                        }
+                        options.limit = Some(value);
+                    }
+                    "on_escape" => {
+                        if options.on_escape.is_some() {
+                            return Err(syn::Error::new(name.span(), "`on_escape` is given twice"));
+                        }
+                        options.on_escape = Some(value);
+                    }
+                    _ => {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            format!("unknown option `{}`", name),
+                        ))
+                    }
+                }
+                continue;
+            }
+            if input.peek(Ident) && input.peek2(token::Paren) {
+                // Could also be a race arm: `Some(x) = { block } => handler`.
+                let fork = input.fork();
+                fork.parse::<Ident>()?;
+                let _content;
+                syn::parenthesized!(_content in fork);
+                if !(fork.peek(Token![;]) || fork.peek(Token![,])) {
+                    break;
+                }
+                let name: Ident = input.parse()?;
+                let content;
+                syn::parenthesized!(content in input);
+                match name.to_string().as_str() {
+                    "copy" => {
+                        options
+                            .copy
+                            .extend(Punctuated::<Expr, Token![,]>::parse_terminated(&content)?);
+                    }
+                    "ref_methods" => {
+                        options
+                            .ref_methods
+                            .extend(Punctuated::<Ident, Token![,]>::parse_terminated(&content)?);
+                    }
+                    "graceful" => {
+                        if options.graceful.is_some() {
+                            return Err(syn::Error::new(name.span(), "`graceful` is given twice"));
+                        }
+                        options.graceful = Some(content.parse()?);
+                    }
+                    "share" => {
+                        options
+                            .share
+                            .get_or_insert_with(Vec::new)
+                            .extend(Punctuated::<ShareEntry, Token![,]>::parse_terminated(
+                                &content,
+                            )?);
+                    }
+                    _ => {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            format!("unknown option `{}`", name),
+                        ))
+                    }
+                }
+                // `share(...)` reads naturally followed by a comma too.
+                if name == "share" && input.peek(Token![,]) {
+                    input.parse::<Token![,]>()?;
+                } else {
+                    input.parse::<Token![;]>()?;
+                }
+                continue;
+            }
+            if !(input.peek(Ident) && input.peek2(Token![;])) {
+                break;
+            }
+            let name: Ident = input.parse()?;
+            input.parse::<Token![;]>()?;
+            match name.to_string().as_str() {
+                "branch_wakers" => options.branch_wakers = true,
+                "explain" => options.explain = Some(name),
+                "biased" | "rotate" => {
+                    let poll_order = match name.to_string().as_str() {
+                        "biased" => PollOrder::Biased,
+                        _ => PollOrder::Rotate,
+                    };
+                    if poll_order_set && options.poll_order != poll_order {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            "`biased` and `rotate` can't be used together",
+                        ));
+                    }
+                    poll_order_set = true;
+                    options.poll_order = poll_order;
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("unknown option `{}`", name),
+                    ))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,49 @@
+use syn::{parse::ParseStream, token, Expr, Ident, Token};
+
+use crate::arms;
+
+/// The `timeout(duration, timer) => handler` pseudo-branch.
+pub(crate) struct Timeout {
+    pub token: Ident,
+    pub duration: Expr,
+    pub timer: Expr,
+    pub handler: Expr,
+}
+
+/// Whether the input continues with `timeout(...) =>`.
+pub(crate) fn peek(input: ParseStream) -> bool {
+    let fork = input.fork();
+    let is_timeout = fork.parse::<Ident>().is_ok_and(|ident| ident == "timeout");
+    if !is_timeout || !fork.peek(token::Paren) {
+        return false;
+    }
+    let parse_args = |fork: ParseStream| {
+        let _content;
+        syn::parenthesized!(_content in fork);
+        Ok(())
+    };
+    parse_args(&fork).is_ok() && fork.peek(Token![=>])
+}
+
+/// Parse the pseudo-branch.
+/// Returns whether a comma is needed after it, as in a `match` arm.
+pub(crate) fn parse(input: ParseStream) -> syn::Result<(Timeout, bool)> {
+    let token: Ident = input.parse()?;
+    let content;
+    syn::parenthesized!(content in input);
+    let duration = content.parse()?;
+    content.parse::<Token![,]>()?;
+    let timer = content.parse()?;
+    content.parse::<Option<Token![,]>>()?;
+    input.parse::<Token![=>]>()?;
+    let (handler, needs_comma) = arms::parse_handler(input)?;
+    Ok((
+        Timeout {
+            token,
+            duration,
+            timer,
+            handler,
+        },
+        needs_comma,
+    ))
+}
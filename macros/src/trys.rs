@@ -1,6 +1,6 @@
 use syn::{parse_quote, visit_mut::VisitMut, Expr, ExprBlock};
 
-pub fn desugar_trys(blocks: &mut Vec<ExprBlock>) {
+pub fn desugar_trys(blocks: &mut [ExprBlock]) {
     let mut replacer = TryReplacer { try_level: 0 };
     blocks.iter_mut().for_each(|block| {
         replacer.visit_expr_block_mut(block);
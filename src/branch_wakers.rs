@@ -0,0 +1,84 @@
+//! Per-block wakers for the `branch_wakers;` option.
+
+use std::{
+    boxed::Box,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    task::{Wake, Waker},
+};
+
+/// One waker per block. Waking one of them marks that block as woken,
+/// then wakes the waker the whole join was last polled with.
+pub struct BranchWakers {
+    shared: Arc<Shared>,
+    wakers: Box<[Waker]>,
+}
+
+struct Shared {
+    parent: Mutex<Option<Waker>>,
+    woken: Box<[AtomicBool]>,
+}
+
+struct BranchWaker {
+    shared: Arc<Shared>,
+    index: usize,
+}
+
+impl Wake for BranchWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.shared.woken[self.index].store(true, Ordering::Release);
+        let parent = self
+            .shared
+            .parent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(parent) = &*parent {
+            parent.wake_by_ref();
+        }
+    }
+}
+
+impl BranchWakers {
+    /// All blocks start out woken, so that they all get their first poll.
+    pub fn new(num: usize) -> Self {
+        let shared = Arc::new(Shared {
+            parent: Mutex::new(None),
+            woken: (0..num).map(|_| AtomicBool::new(true)).collect(),
+        });
+        let wakers = (0..num)
+            .map(|index| {
+                Waker::from(Arc::new(BranchWaker {
+                    shared: shared.clone(),
+                    index,
+                }))
+            })
+            .collect();
+        Self { shared, wakers }
+    }
+    /// Remember the waker the join is being polled with.
+    /// Call this before polling any block.
+    pub fn register(&self, parent: &Waker) {
+        let mut stored = self
+            .shared
+            .parent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match &*stored {
+            Some(stored) if stored.will_wake(parent) => {}
+            _ => *stored = Some(parent.clone()),
+        }
+    }
+    /// Check whether the block was woken since it was last polled,
+    /// clearing the flag.
+    pub fn take_woken(&self, index: usize) -> bool {
+        self.shared.woken[index].swap(false, Ordering::Acquire)
+    }
+    pub fn waker(&self, index: usize) -> &Waker {
+        &self.wakers[index]
+    }
+}
@@ -0,0 +1,58 @@
+//! The signal given to the blocks by the `graceful(...)` option.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+/// Tells the blocks of a macro with the [`graceful` option](crate#graceful)
+/// that the macro is stopping, and that they should wind down.
+#[derive(Debug)]
+pub struct Cancelled {
+    cancelled: AtomicBool,
+}
+
+impl Cancelled {
+    pub(crate) fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Whether the macro is stopping.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Wait until the macro is stopping.
+    pub fn cancelled(&self) -> WaitCancelled<'_> {
+        WaitCancelled { signal: self }
+    }
+}
+
+/// The future returned by [`Cancelled::cancelled`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct WaitCancelled<'a> {
+    signal: &'a Cancelled,
+}
+
+impl Future for WaitCancelled<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        // No need to keep the waker: once the macro is stopping,
+        // it polls every block that is still running, without waiting to be woken.
+        if self.signal.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -26,6 +26,250 @@
 //!
 //! The results are returned as a tuple.
 //!
+//! ### Named blocks
+//!
+//! Blocks can be named with `name = { ... }`. The results are then returned
+//! in a struct with a field for each name, instead of a tuple.
+//!
+//! ```
+//! # async {
+//! let res = enjoin::join!(
+//!     user = {
+//!         // Code goes here
+//!         "alice"
+//!     },
+//!     count = {
+//!         // Code goes here
+//!         3
+//!     }
+//! );
+//! assert_eq!(res.user, "alice");
+//! assert_eq!(res.count, 3);
+//! # };
+//! ```
+//!
+//! Either all the blocks are named or none of them.
+//! This works in `join!`, `try_join!`, and `join_auto_borrow!`.
+//! With `try_join!`, the struct is wrapped in `Ok`/`Some`.
+//!
+//! ### Racing
+//!
+//! `race!` takes blocks in the same way, but only waits for the first block
+//! to finish. The value of that block is returned, and the other blocks
+//! are stopped and dropped.
+//! All the blocks must evaluate to the same type.
+//!
+//! ```
+//! # async {
+//! let winner = enjoin::race!(
+//!     {
+//!         // Code goes here
+//!         "first"
+//!     },
+//!     {
+//!         // Code goes here
+//!         "second"
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! Like in `tokio::select!`, blocks in `race!` can be given as
+//! `pattern = { block } => handler` arms.
+//! When the block finishes, its value is matched against the pattern,
+//! and the handler is run. The value of the handler becomes the value of
+//! the race.
+//! If the value doesn't match the pattern, that branch is disabled and the
+//! race continues with the remaining blocks.
+//! If all branches are disabled, the `else => handler` arm runs
+//! (or, if there is no `else` arm, the macro panics).
+//!
+//! ```
+//! # async {
+//! # async fn recv() -> Option<i32> { None }
+//! let msg = enjoin::race!(
+//!     Some(msg) = {
+//!         // Code goes here
+//!         recv().await
+//!     } => msg,
+//!     n = {
+//!         // Code goes here
+//!         5
+//!     } => {
+//!         println!("got {n} first");
+//!         n
+//!     },
+//!     else => 0,
+//! );
+//! # };
+//! ```
+//!
+//! The handlers are regular code, not run concurrently. They are run after
+//! all the blocks have been dropped, so they can use anything the blocks used,
+//! and branching statements and `?` in them work as they do anywhere else.
+//!
+//! ### Try-joining
+//!
+//! `try_join!` is for blocks that evaluate to `Result` or `Option`.
+//! If every block succeeds, the results are returned as a tuple wrapped
+//! in `Ok`/`Some`.
+//! As soon as any block evaluates to `Err`/`None`, the other blocks are
+//! stopped and dropped, and that `Err`/`None` is returned.
+//! The blocks must all have the same error type.
+//!
+//! ```
+//! # async {
+//! let res: Result<(i32, &str), &str> = enjoin::try_join!(
+//!     {
+//!         // Code goes here
+//!         Ok(5)
+//!     },
+//!     {
+//!         // Code goes here
+//!         Err("oh no")
+//!     }
+//! );
+//! assert_eq!(res, Err("oh no"));
+//! # };
+//! ```
+//!
+//! `try_race!` is the opposite: the first block to evaluate to `Ok`/`Some`
+//! wins, and its value is returned in `Ok`. The other blocks are
+//! stopped and dropped.
+//! The success types of the blocks must be the same, but the error types
+//! may differ.
+//! Failing blocks are ignored until every block has failed. Then, the
+//! residuals of the failures are returned as a tuple in `Err`.
+//! (The residual of an `Err(e)` is an `Err(e)` of type
+//! `Result<Infallible, E>`; the residual of a `None` is a `None`.)
+//!
+//! ```
+//! # async {
+//! let res = enjoin::try_race!(
+//!     {
+//!         // Code goes here
+//!         "not a number".parse::<i32>()
+//!     },
+//!     {
+//!         // Code goes here
+//!         "5".parse::<i32>()
+//!     }
+//! );
+//! assert_eq!(res, Ok(5));
+//! # };
+//! ```
+//!
+//! Note that this is different from using `?` inside the blocks:
+//! `?` makes the surrounding function return (see [below](#try-operator--support)),
+//! while `try_join!` and `try_race!` only look at the value each block
+//! evaluates to.
+//!
+//! Other types work too, if they implement [`polyfill::Try`].
+//! For `try_join!`, which builds its output from a residual, their residual
+//! must also implement [`polyfill::Residual`].
+//!
+//! ### Cleaning up cancelled blocks
+//!
+//! A block that is still running when the macro stops it
+//! (because another block won a race, failed a `try_join!`, or jumped out
+//! with `break`, `continue`, `return`, or `?`) is dropped at its `.await`.
+//! Code given as `on_cancel { ... }` after the block runs synchronously when
+//! that happens, so there is no need for a hand-written `Drop` guard.
+//!
+//! ```
+//! # async fn upload() {}
+//! # async fn download() {}
+//! # async {
+//! let mut aborted = false;
+//! enjoin::race!(
+//!     {
+//!         upload().await;
+//!     } on_cancel {
+//!         aborted = true;
+//!     },
+//!     {
+//!         download().await;
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! The code only runs for a block that was started and hasn't finished,
+//! right after all the blocks are dropped (and before the
+//! [`on_escape` closure](#on_escape), if there is one).
+//! It can't `.await`, and can't jump out of the macro.
+//! In `join!`, it borrows what it uses for as long as the blocks run,
+//! just like another block would.
+//! In `join_auto_borrow!`, it shares captures with the blocks like they do
+//! with each other.
+//! `on_cancel` is available in all of the macros except `join_for!`.
+//!
+//! ### Timeouts
+//!
+//! A `timeout(duration, timer) => handler` pseudo-branch stops the blocks
+//! if they haven't finished (or, in races, if none has won)
+//! after `duration`. The blocks are dropped, running their
+//! [`on_cancel` code](#cleaning-up-cancelled-blocks), then the handler runs.
+//! Its value becomes the value of the macro.
+//!
+//! ```
+//! # use core::time::Duration;
+//! # async fn fetch() -> u32 { 1 }
+//! # struct MyTimer;
+//! # impl enjoin::Timer for MyTimer {
+//! #     fn sleep(&self, _: Duration) -> impl core::future::Future<Output = ()> {
+//! #         core::future::ready(())
+//! #     }
+//! # }
+//! # async {
+//! # let timer = MyTimer;
+//! let value = enjoin::race!(
+//!     { fetch().await },
+//!     timeout(Duration::from_secs(5), &timer) => 0,
+//! );
+//! # };
+//! ```
+//!
+//! Like the handlers of race arms, the handler runs after the blocks are dropped,
+//! outside of any async block, so it can use what they borrowed and it can
+//! `break`, `continue`, `return`, or use `?` as usual.
+//! In `join!` and the try macros, that is how you would usually leave.
+//! A block that finishes on the same poll as the timer still counts as finished in time.
+//!
+//! The timer is anything implementing [`Timer`]: a single `sleep` method.
+//! The `tokio` and `async-std` features provide `timer::Tokio`
+//! and `timer::AsyncStd`.
+//! Timing out isn't an escape, so it doesn't call the [`on_escape`](#on_escape)
+//! closure, and it drops the blocks right away even with [`graceful`](#graceful).
+//!
+//! ### Joining over an iterator
+//!
+//! `join_for!` runs its body once for every item of an iterator,
+//! all concurrently, and returns the results in a `Vec`, in the order
+//! of the items.
+//!
+//! ```
+//! # async {
+//! let lengths = enjoin::join_for!(for name in ["a", "bb", "ccc"] {
+//!     // Code goes here
+//!     name.len()
+//! });
+//! assert_eq!(lengths, [1, 2, 3]);
+//! # };
+//! ```
+//!
+//! The iterator is consumed entirely before any of the copies of the body
+//! are polled. Each copy is boxed, so this needs an allocator.
+//!
+//! `join_for_auto_borrow!` is to `join_for!` what `join_auto_borrow!` is
+//! to `join!`. Since the copies all run concurrently, everything the body
+//! borrows mutably is shared between them.
+//!
+//! Everything described below (branching statements, `?`)
+//! works in `race!`, `try_join!`, `try_race!`, and `join_for!` too.
+//! A `break`, `continue`, or `return` in any copy of the body stops
+//! and drops all of them.
+//!
 //! ## Features
 //!
 //! This features are things that you can already do in regular blocks.
@@ -115,6 +359,30 @@
 //! # };
 //! ```
 //!
+//! If a value is only ever read or assigned to (`count += 1`, `x = y`, `if x > 0`)
+//! and the macro can tell its type is `Copy`, it is put in a `Cell` instead,
+//! which needs no borrow checking at runtime.
+//! The macro can tell from an assignment of a literal, like `count = 0` or
+//! `done = true`. Updating with a literal, like `count += 1`, isn't enough
+//! on its own, since non-`Copy` types can implement `AddAssign` too, so a
+//! value that is only ever updated stays in a RefCell.
+//! To put such a value, or any other `Copy` value, in a `Cell` anyway,
+//! list it in the `copy` option, as in `copy(count);` (see [below](#copy)).
+//!
+//! Each shared value gets its own RefCell. A block only borrows it around the
+//! statements that use the value, so blocks using different values never
+//! get in each other's way.
+//! (A `let` statement that uses the value keeps it borrowed until the end of the
+//! block, since what it binds might hold on to the borrow.)
+//! Async blocks and async closures inside the blocks, including `async move` ones,
+//! and closures stored in a variable with `let`,
+//! borrow the shared values on their own in the same way, whenever they run.
+//! Other closures run within the borrows of the statement they are in.
+//!
+//! In methods, `self` is handled like any other variable: blocks using different
+//! fields of `self` share nothing, and only the fields used by several blocks
+//! go in cells.
+//!
 //! The macro makes sure the RefCell will never panic by disallowing
 //! shared borrows from lasting across await yieldpoints.
 //!
@@ -134,6 +402,307 @@
 //! # };
 //! ```
 //!
+//! When the borrow is stored in a variable like this, the macro reports
+//! which variable holds the borrow and where the `.await` is.
+//! Less obvious cases still fail to compile, but with a borrow checker error
+//! about the macro's internal variables.
+//!
+//! ## Options
+//!
+//! Options can be given at the start of any of the macros,
+//! each followed by a `;`. Options with a value are written `name = value;`.
+//!
+//! ### `biased` and `rotate`
+//!
+//! Each time the join is polled, it polls the unfinished blocks one by one.
+//! The order of that matters when more than one block is ready:
+//! in a race, the block polled first wins, and in `join!`, code in the block
+//! polled first runs first.
+//!
+//! By default (or with the `biased` option), blocks are always polled in the
+//! order they are written. This is predictable, but if an early block is
+//! always ready, later blocks are always delayed behind it, and lose every
+//! race against it.
+//!
+//! With the `rotate` option, the first poll starts from the first block,
+//! the next poll starts from the second block, and so on, wrapping around.
+//!
+//! ```
+//! # async {
+//! let winner = enjoin::race!(
+//!     rotate;
+//!     {
+//!         // Code goes here
+//!         1
+//!     },
+//!     {
+//!         // Code goes here
+//!         2
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! ### `branch_wakers`
+//!
+//! By default, whenever the join is woken, every unfinished block gets polled,
+//! even if only one of them was actually woken.
+//! With many blocks, most of these polls are wasted.
+//!
+//! The `branch_wakers` option gives each block its own waker, so that only
+//! the blocks that were woken get polled.
+//! This costs some allocations when the join starts.
+//!
+//! ```
+//! # async {
+//! enjoin::join!(
+//!     branch_wakers;
+//!     {
+//!         // Code goes here
+//!     },
+//!     {
+//!         // Code goes here
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! ### `copy`
+//!
+//! In `join_auto_borrow!` and `join_for_auto_borrow!`, `copy(a, b.field);`
+//! puts the listed values in a `Cell` instead of a `RefCell`
+//! (see [shared borrowing](#shared-borrowing-support)).
+//! The listed values must be `Copy`, shared mutably between blocks,
+//! and only read or assigned to, never borrowed mutably or used as a method
+//! receiver.
+//!
+//! ```
+//! # async {
+//! #[derive(Clone, Copy)]
+//! struct Point(i32, i32);
+//! let mut position = Point(0, 0);
+//! enjoin::join_auto_borrow!(
+//!     copy(position);
+//!     {
+//!         // Code goes here
+//!         position = Point(1, 2);
+//!     },
+//!     {
+//!         // Code goes here
+//!         let Point(x, y) = position;
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! ### `share`
+//!
+//! `join_auto_borrow!` guesses which variables the blocks share and how
+//! (see [troubleshooting](#troubleshooting)). To skip the guessing,
+//! list the shared places in `share(...)`:
+//! * `mut place` is shared mutably, so it is put in a cell.
+//! * `place` is shared immutably, and left alone.
+//! * `move place` is moved into a block, and left alone.
+//!
+//! Then only the places listed with `mut` are put in cells, even if their
+//! names are capitalized or they look like function calls.
+//! Listing a place that none of the blocks use is an error.
+//! `share(...)` may be followed by a `,` instead of a `;`.
+//!
+//! ```
+//! # async {
+//! let mut log = Vec::new();
+//! let names = vec!["a", "b"];
+//! enjoin::join_auto_borrow!(
+//!     share(mut log, names),
+//!     {
+//!         // Code goes here
+//!         let first = names.first();
+//!         log.push(first);
+//!     },
+//!     {
+//!         // Code goes here
+//!         log.push(names.get(1));
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! ### `ref_methods`
+//!
+//! `join_auto_borrow!` knows which common methods of std types only borrow
+//! their receiver immutably (`len`, `iter`, `get`, `contains`, `is_empty`,
+//! `clone`, ...), so a variable the blocks only call those on is shared by
+//! plain reference, with no cell. List more such methods, like ones of your own
+//! types, in `ref_methods(...)`.
+//!
+//! ```
+//! # async {
+//! struct Queue(Vec<u8>);
+//! impl Queue {
+//!     fn peek(&self) -> Option<&u8> {
+//!         self.0.last()
+//!     }
+//! }
+//! let queue = Queue(vec![1, 2]);
+//! enjoin::join_auto_borrow!(
+//!     ref_methods(peek);
+//!     {
+//!         // Code goes here
+//!         let top = queue.peek();
+//!     },
+//!     {
+//!         // Code goes here
+//!         let top = queue.peek();
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! ### `explain`
+//!
+//! With `explain;`, `join_auto_borrow!` reports what it decided about each
+//! variable the blocks use: which blocks use it, whether it is only read, and
+//! whether it is shared through a `Cell`, a `RefCell`, or not at all.
+//! The report is a warning at each use of the variable
+//! (shown as the use of a deprecated `__enjoin_explain`, since that is the only
+//! way a macro can warn on stable Rust). Remove the option once you're done.
+//!
+//! ```
+//! # async {
+//! let mut count = 0;
+//! enjoin::join_auto_borrow!(
+//!     explain;
+//!     {
+//!         // warning: `count` is used by blocks 1 and 2 and is changed
+//!         // and its type is `Copy`, so it is shared through a `Cell`
+//!         count += 1;
+//!     },
+//!     {
+//!         count = 0;
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! ### `on_escape`
+//!
+//! When a block jumps out of the macro with `break`, `continue`, `return`, or `?`,
+//! the outputs of the blocks that already finished are dropped along with the
+//! blocks that didn't.
+//! With `on_escape = |partial| { ... };`, the closure is called with those outputs
+//! right before the jump, so finished work can still be recorded.
+//! It gets an [`enjoin::Partial`](Partial) holding a tuple with an `Option` per block,
+//! which is `Some` for the blocks that finished.
+//!
+//! ```
+//! # async {
+//! # async fn upload(_: &str) -> u32 { 0 }
+//! let mut uploaded = Vec::new();
+//! 'batch: loop {
+//!     enjoin::join!(
+//!         on_escape = |partial| {
+//!             let (a, _) = partial.into_inner();
+//!             uploaded.extend(a);
+//!         };
+//!         { upload("a").await },
+//!         {
+//!             upload("b").await;
+//!             break 'batch;
+//!         }
+//!     );
+//! }
+//! # };
+//! ```
+//!
+//! The blocks have been dropped by the time the closure is called,
+//! so it can use the variables they borrowed.
+//! Only escapes call it: in `try_join!`, a block that fails doesn't,
+//! since its error is the macro's output. In `try_race!`, the outputs are
+//! the failures of the blocks that failed so far
+//! (as residuals, like `Result<Infallible, E>`).
+//! This option is available in `join!`, `try_join!`, `try_race!`,
+//! and `join_auto_borrow!`. With named blocks, the outputs are still a tuple,
+//! in the order of the blocks.
+//!
+//! ### `graceful`
+//!
+//! When the macro stops while some blocks are still running
+//! (a block jumped out with `break`, `continue`, `return`, or `?`,
+//! a race was won, or a `try_join!` failed),
+//! those blocks are dropped right away.
+//! With `graceful(signal, N);`, they are told first, through an
+//! [`enjoin::Cancelled`](Cancelled) named `signal`, and get polled up to `N`
+//! more times to wind down. After that, or as soon as they have all finished,
+//! the macro stops as it would have.
+//!
+//! ```
+//! # async fn fetch() {}
+//! # async fn flush_cache() {}
+//! # async {
+//! enjoin::race!(
+//!     graceful(signal, 3);
+//!     {
+//!         fetch().await;
+//!     },
+//!     {
+//!         signal.cancelled().await;
+//!         // The race is over; write things down before we go.
+//!         flush_cache().await;
+//!     }
+//! );
+//! # };
+//! ```
+//!
+//! Blocks can wait for the signal with `signal.cancelled().await`,
+//! or check it with `signal.is_cancelled()`.
+//! The extra polls come one after another, without waiting for the blocks
+//! to wake the macro, so a block that awaits something slow may not get to finish.
+//! What the blocks evaluate to while winding down is dropped, except that
+//! in `join!` and `try_join!` the outputs still count for the
+//! [`on_escape` closure](#on_escape). Only the first escape is taken.
+//! This option is not available in `join_for!`.
+//!
+//! ### `limit`
+//!
+//! `join_for!` starts a copy of its body for every item at once.
+//! With `limit = N;`, at most `N` copies run at the same time.
+//! The iterator is only advanced when a copy finishes, and the copy for the
+//! next item starts right away.
+//! The results are still returned in the order of the items.
+//! A `break`, `continue`, `return`, or `?` stops all running copies,
+//! and the remaining items are never started.
+//!
+//! ```
+//! # async {
+//! let doubled = enjoin::join_for!(limit = 2; for i in 0..10 {
+//!     // Code goes here
+//!     i * 2
+//! });
+//! # };
+//! ```
+//!
+//! `N` can be any expression evaluating to a `usize` of at least 1.
+//! This option is only available in `join_for!` and `join_for_auto_borrow!`.
+//! With `branch_wakers`, there is one waker per running copy rather than per
+//! item.
+//!
+//! ## `no_std`
+//!
+//! This crate is `no_std`. The code generated by `join!`, `join_auto_borrow!`,
+//! `race!`, `try_join!`, and `try_race!` only needs `core`.
+//! The rest is behind cargo features, both enabled by default:
+//! * `alloc` is needed for `join_for!` and `join_for_auto_borrow!`.
+//! * `std` (which implies `alloc`) is needed for the `branch_wakers` option.
+//!
+//! Two more features, off by default, add timers for [timeouts](#timeouts):
+//! `tokio` for `timer::Tokio` and `async-std` for `timer::AsyncStd`.
+//! Both imply `std`.
+//!
+//! ```toml
+//! enjoin = { version = "0.2", default-features = false }
+//! ```
+//!
 //! ## More information
 //!
 //! There is [a blog post](https://wishawa.github.io/posts/enjoin) detailing
@@ -143,38 +712,165 @@
 //!
 //! ## Troubleshooting
 //!
-//! * If branching statements and/or captured variables are hidden
-//!     in another macro, *enjoin* wouldn't be able to transform them.
-//!     This will usually cause compilation failure.
-//!
-//! 	```rust
-//!     # async {
-//! 	enjoin::join!({ vec![
-//!         1, 2, 3
-//! 		// enjoin can't see the code in this vec!
-//! 	] });
-//!     # };
-//! 	```
+//! * *enjoin* can see inside the standard macros that take expressions
+//!   (`vec!`, `format!`, `println!`, `assert_eq!`, `write!`, ...),
+//!   `join!`/`try_join!` from `tokio` and `futures`, and the blocks of its own
+//!   macros (but not their options, arm patterns and handlers, or `on_cancel` code).
+//!   If branching statements and/or captured variables are hidden
+//!   in any other macro, *enjoin* wouldn't be able to transform them.
+//!   This will usually cause compilation failure.
+//!
+//!   ```rust
+//!   # async {
+//!   enjoin::join!({ vec![
+//!       1; 3
+//!       // enjoin can't see the code in this vec!, since it isn't a list
+//!   ] });
+//!   # };
+//!   ```
 //!
 //! ---
 //!
-//! * If an `await` is hidden inside a macro, `join_auto_borrow!` won't be able
-//!     to unlock the RefCell for the yieldpoint, leading to a RefCell panic.
-//!     This limitation means you can't nest `enjoin::join!` or `tokio::join!`
-//!     within `enjoin::join_auto_borrow!`.
+//! * Nested macros that await are handled: `join_auto_borrow!` unlocks the
+//!   RefCells around `tokio::join!`, `select!`, its own macros, and any other macro
+//!   with `.await` in its input. The blocks of a nested *enjoin* macro borrow
+//!   the shared values on their own, just like the outer blocks do.
+//!   But if an `await` is hidden inside the definition of some other macro,
+//!   `join_auto_borrow!` won't be able to unlock the RefCell for the yieldpoint,
+//!   leading to a RefCell panic if that macro is in a statement that also uses
+//!   a shared value.
 //!
 //! ---
 //!
 //! * With only syntactic information, *enjoin* can only guess whether or not a
-//!     name is a borrowed variable, and whether or not that borrow is mutable.
-//!     We have heuristics, but even so the macro may end up RefCell-ing
-//!     immutable borrows, constants, or function pointers sometimes.
-//!     You can help the macro by writing `(&mut var).method()` or
-//!     `(&var).method()` instead of `var.method()`.
+//!   name is a borrowed variable, and whether or not that borrow is mutable.
+//!   We have heuristics, but even so the macro may end up RefCell-ing
+//!   immutable borrows, constants, or function pointers sometimes.
+//!   Method calls count as mutable uses unless the method is a known `&self`
+//!   method (see the [`ref_methods` option](#ref_methods)).
+//!   You can help the macro by writing `(&mut var).method()` or
+//!   `(&var).method()` instead of `var.method()`,
+//!   or list the shared variables yourself with the [`share` option](#share).
+//!   The [`explain` option](#explain) shows what the macro guessed.
 //!
 //! ## Sample expansion
 //! See [here](https://github.com/wishawa/enjoin/blob/main/tests/sample_expansion.rs).
-pub use enjoin_macro::{join, join_auto_borrow};
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub use enjoin_macro::{
+    join, join_auto_borrow, join_for, join_for_auto_borrow, race, try_join, try_race,
+};
+
+#[cfg(feature = "std")]
+mod branch_wakers;
+mod cancelled;
+pub mod timer;
+
+pub use cancelled::{Cancelled, WaitCancelled};
+pub use timer::Timer;
+
+/// The outputs of the blocks that had finished when another block escaped the macro.
+///
+/// Passed to the closure given with the [`on_escape` option](crate#on_escape).
+/// Holds a tuple with an `Option` for each block, in the order of the blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Partial<T>(pub T);
+
+impl<T> Partial<T> {
+    /// Take the tuple of outputs.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    //! Used by the code the macros generate. Not public API.
+    #[cfg(feature = "alloc")]
+    pub use alloc::{boxed::Box, vec::Vec};
+    use core::cell::RefMut;
+    pub use core::cell::{Cell, RefCell};
+
+    #[cfg(feature = "std")]
+    pub use crate::branch_wakers::BranchWakers;
+
+    /// Borrow a shared capture, with a clearer message than `RefCell::borrow_mut`'s if it fails.
+    #[track_caller]
+    pub fn borrow_mut<'a, T>(cell: &'a RefCell<T>, name: &str) -> RefMut<'a, T> {
+        match cell.try_borrow_mut() {
+            Ok(borrowed) => borrowed,
+            Err(_) => {
+                panic!("`{name}` is shared between blocks and is already borrowed by another one")
+            }
+        }
+    }
+
+    /// The signal for the `graceful(...)` option.
+    pub fn new_cancelled() -> crate::Cancelled {
+        crate::Cancelled::new()
+    }
+
+    /// Tell the blocks that the macro is stopping.
+    pub fn cancel(signal: &crate::Cancelled) {
+        signal.cancel();
+    }
+
+    /// Call the `on_escape` closure.
+    /// Going through a function lets the closure's argument type be inferred.
+    pub fn on_escape<T>(f: impl FnOnce(crate::Partial<T>), partial: T) {
+        f(crate::Partial(partial))
+    }
+
+    /// Borrow `self` mutably with method syntax,
+    /// which works in both `&mut self` and `mut self` methods.
+    pub trait Reborrow {
+        fn __enjoin_rb(&mut self) -> &mut Self {
+            self
+        }
+    }
+    impl<T: ?Sized> Reborrow for T {}
+
+    /// A block's `on_cancel` code, which runs when this is dropped
+    /// if the block was started and hasn't finished.
+    /// It is declared before the blocks, so that it is dropped after them.
+    pub struct OnCancel<F: FnOnce()> {
+        on_cancel: Option<F>,
+        running: bool,
+    }
+
+    impl<F: FnOnce()> OnCancel<F> {
+        pub fn new(on_cancel: F) -> Self {
+            Self {
+                on_cancel: Some(on_cancel),
+                running: false,
+            }
+        }
+        pub fn set_running(&mut self, running: bool) {
+            self.running = running;
+        }
+    }
+
+    impl<F: FnOnce()> Drop for OnCancel<F> {
+        fn drop(&mut self) {
+            if self.running {
+                if let Some(on_cancel) = self.on_cancel.take() {
+                    on_cancel();
+                }
+            }
+        }
+    }
+
+    /// Tell the compiler that the closure takes items of the iterator,
+    /// so that patterns in the body can be type-checked.
+    pub fn item_fn<I: Iterator, O, F: Fn(I::Item) -> O>(_iter: &I, f: F) -> F {
+        f
+    }
+}
 
 pub mod polyfill {
     //! Polyfill for the rust Try (and related) trait that is currently unstable.
@@ -240,6 +936,23 @@ pub mod polyfill {
         }
     }
 
+    /// Turns a [`Try::Residual`] back into the matching [`Try`] type with output `O`,
+    /// like `Result<Infallible, E>` into `Result<O, E>`.
+    ///
+    /// `try_join!` uses this to build its output, so a custom `Try` type used
+    /// with it needs this implemented for its residual too.
+    pub trait Residual<O> {
+        type TryType: Try<Output = O, Residual = Self>;
+    }
+
+    impl<T> Residual<T> for Option<Infallible> {
+        type TryType = Option<T>;
+    }
+
+    impl<T, E> Residual<T> for Result<Infallible, E> {
+        type TryType = Result<T, E>;
+    }
+
     impl<T> FromResidual for Option<T> {
         fn from_residual(_residual: Option<Infallible>) -> Self {
             None
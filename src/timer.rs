@@ -0,0 +1,43 @@
+//! Timers for the `timeout(duration, timer) => handler` pseudo-branch.
+
+use core::{future::Future, time::Duration};
+
+/// A way to wait for some time, usually provided by the async runtime.
+/// Used by the [`timeout` pseudo-branch](crate#timeouts).
+///
+/// Implementations for tokio and async-std are behind the `tokio`
+/// and `async-std` features.
+pub trait Timer {
+    /// Wait for `duration`.
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+impl<T: Timer + ?Sized> Timer for &T {
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        T::sleep(self, duration)
+    }
+}
+
+/// The timer of the tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Timer for Tokio {
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// The timer of the async-std runtime.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Timer for AsyncStd {
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        async_std::task::sleep(duration)
+    }
+}
@@ -26,3 +26,98 @@ async fn race() {
     };
     assert_eq!(res, 3)
 }
+
+#[pollster::test]
+async fn race_macro() {
+    let res = enjoin::race!(
+        {
+            YieldFor(4).await;
+            4
+        },
+        {
+            YieldFor(6).await;
+            6
+        },
+        {
+            YieldFor(3).await;
+            3
+        }
+    );
+    assert_eq!(res, 3)
+}
+
+#[pollster::test]
+async fn race_macro_first_wins_tie() {
+    let res = enjoin::race!(
+        {
+            YieldFor(2).await;
+            "a"
+        },
+        {
+            YieldFor(2).await;
+            "b"
+        }
+    );
+    assert_eq!(res, "a")
+}
+
+#[pollster::test]
+async fn race_macro_escape() {
+    let res = 'out: {
+        enjoin::race!(
+            {
+                YieldFor(2).await;
+                break 'out 2;
+            },
+            {
+                YieldFor(5).await;
+                5
+            }
+        )
+    };
+    assert_eq!(res, 2)
+}
+
+#[pollster::test]
+async fn race_macro_loser_dropped() {
+    let mut record = Vec::new();
+    for i in 0..3 {
+        let res = enjoin::race!(
+            {
+                YieldFor(1).await;
+                if i == 1 {
+                    continue;
+                }
+                i
+            },
+            {
+                YieldFor(10).await;
+                100
+            }
+        );
+        record.push(res);
+    }
+    assert_eq!(record, [0, 2]);
+}
+
+#[pollster::test]
+async fn race_macro_try() {
+    async fn inner(fail: bool) -> Result<i32, &'static str> {
+        let v = enjoin::race!(
+            {
+                YieldFor(1).await;
+                if fail {
+                    Err("failed")?;
+                }
+                1
+            },
+            {
+                YieldFor(3).await;
+                3
+            }
+        );
+        Ok(v)
+    }
+    assert_eq!(inner(false).await, Ok(1));
+    assert_eq!(inner(true).await, Err("failed"));
+}
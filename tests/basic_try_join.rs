@@ -46,3 +46,153 @@ async fn basic_try_join_fail() {
     };
     assert!(!res);
 }
+
+#[pollster::test]
+async fn try_join_macro_successful() {
+    let res = enjoin::try_join!(
+        {
+            YieldFor(3).await;
+            Ok::<_, &str>(3)
+        },
+        {
+            YieldFor(4).await;
+            Ok("hello")
+        },
+        {
+            YieldFor(2).await;
+            Ok("hi")
+        }
+    );
+    assert_eq!(res, Ok((3, "hello", "hi")));
+}
+
+#[pollster::test]
+async fn try_join_macro_fail() {
+    let mut finished = false;
+    let res = enjoin::try_join!(
+        {
+            YieldFor(3).await;
+            Ok(3)
+        },
+        {
+            YieldFor(2).await;
+            Err::<(), _>("failed")
+        },
+        {
+            YieldFor(10).await;
+            finished = true;
+            Ok(())
+        }
+    );
+    assert_eq!(res, Err("failed"));
+    assert!(!finished);
+}
+
+#[pollster::test]
+async fn try_join_macro_option() {
+    let res = enjoin::try_join!(
+        {
+            YieldFor(3).await;
+            Some(3)
+        },
+        {
+            YieldFor(1).await;
+            None::<()>
+        }
+    );
+    assert_eq!(res, None);
+}
+
+#[pollster::test]
+async fn try_join_macro_questionmark_returns() {
+    async fn inner() -> Result<i32, &'static str> {
+        let res = enjoin::try_join!(
+            {
+                YieldFor(1).await;
+                Err::<(), _>("from question mark")?;
+                Ok(1)
+            },
+            {
+                YieldFor(3).await;
+                Err::<(), _>("from tail")
+            }
+        );
+        panic!("should have returned, got {:?}", res);
+    }
+    assert_eq!(inner().await, Err("from question mark"));
+}
+
+#[pollster::test]
+async fn try_join_macro_escape() {
+    let res = 'a: {
+        let _ = enjoin::try_join!(
+            {
+                YieldFor(3).await;
+                Ok::<_, ()>(3)
+            },
+            {
+                YieldFor(1).await;
+                if 1 + 1 == 2 {
+                    break 'a 5;
+                }
+                Ok(())
+            }
+        );
+        0
+    };
+    assert_eq!(res, 5);
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome<T> {
+    Done(T),
+    Failed(&'static str),
+}
+
+impl<T> enjoin::polyfill::Try for Outcome<T> {
+    type Output = T;
+    type Residual = Outcome<core::convert::Infallible>;
+
+    fn from_output(output: T) -> Self {
+        Outcome::Done(output)
+    }
+    fn branch(self) -> core::ops::ControlFlow<Self::Residual, T> {
+        match self {
+            Outcome::Done(output) => core::ops::ControlFlow::Continue(output),
+            Outcome::Failed(reason) => core::ops::ControlFlow::Break(Outcome::Failed(reason)),
+        }
+    }
+}
+
+impl<T> enjoin::polyfill::FromResidual for Outcome<T> {
+    fn from_residual(residual: Outcome<core::convert::Infallible>) -> Self {
+        match residual {
+            Outcome::Done(never) => match never {},
+            Outcome::Failed(reason) => Outcome::Failed(reason),
+        }
+    }
+}
+
+impl<T> enjoin::polyfill::Residual<T> for Outcome<core::convert::Infallible> {
+    type TryType = Outcome<T>;
+}
+
+#[pollster::test]
+async fn try_join_macro_custom_type() {
+    let res = enjoin::try_join!(
+        {
+            YieldFor(1).await;
+            Outcome::Done(1)
+        },
+        { Outcome::Done("two") }
+    );
+    assert_eq!(res, Outcome::Done((1, "two")));
+    let res = enjoin::try_join!(
+        {
+            YieldFor(1).await;
+            Outcome::Done(1)
+        },
+        { Outcome::<()>::Failed("nope") }
+    );
+    assert_eq!(res, Outcome::Failed("nope"));
+}
@@ -45,3 +45,75 @@ async fn try_race_fail() {
     };
     assert_eq!(a, Err(("no", "another no")));
 }
+
+#[pollster::test]
+async fn try_race_macro_successful() {
+    let a = enjoin::try_race!(
+        {
+            YieldFor(3).await;
+            Ok::<_, &str>("yes")
+        },
+        {
+            YieldFor(1).await;
+            Err("no")
+        },
+        {
+            YieldFor(5).await;
+            Ok::<_, &str>("yes2")
+        }
+    );
+    assert_eq!(a, Ok("yes"));
+}
+
+#[pollster::test]
+async fn try_race_macro_fail() {
+    let a = enjoin::try_race!(
+        {
+            YieldFor(3).await;
+            Err::<(), _>("no")
+        },
+        {
+            YieldFor(1).await;
+            Err::<(), _>("another no")
+        }
+    );
+    let Err((Err(first), Err(second))) = a else {
+        panic!("should have failed");
+    };
+    assert_eq!((first, second), ("no", "another no"));
+}
+
+#[pollster::test]
+async fn try_race_macro_option() {
+    let a = enjoin::try_race!(
+        {
+            YieldFor(1).await;
+            None
+        },
+        {
+            YieldFor(3).await;
+            Some(3)
+        }
+    );
+    assert_eq!(a, Ok(3));
+}
+
+#[pollster::test]
+async fn try_race_macro_escape() {
+    let a = 'race: {
+        enjoin::try_race!(
+            {
+                YieldFor(3).await;
+                Ok::<_, ()>(3)
+            },
+            {
+                YieldFor(1).await;
+                if 1 + 1 == 2 {
+                    break 'race Ok(100);
+                }
+                Err(())
+            }
+        )
+    };
+    assert_eq!(a, Ok(100));
+}
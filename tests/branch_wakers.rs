@@ -0,0 +1,115 @@
+mod utils;
+use utils::YieldFor;
+
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Counts how many times it is polled.
+/// Finishes (once woken through `waker`) after `done` is set.
+struct CountPolls<'a> {
+    polls: &'a Cell<usize>,
+    done: &'a Cell<bool>,
+    waker: &'a Cell<Option<Waker>>,
+}
+
+impl Future for CountPolls<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.polls.set(self.polls.get() + 1);
+        if self.done.get() {
+            Poll::Ready(())
+        } else {
+            self.waker.set(Some(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+}
+
+#[pollster::test]
+async fn only_woken_polled() {
+    let polls = Cell::new(0);
+    let done = Cell::new(false);
+    let waker = Cell::new(None::<Waker>);
+    enjoin::join!(
+        branch_wakers;
+        {
+            YieldFor(10).await;
+            done.set(true);
+            waker.take().unwrap().wake();
+        },
+        {
+            CountPolls {
+                polls: &polls,
+                done: &done,
+                waker: &waker,
+            }
+            .await;
+        }
+    );
+    assert_eq!(polls.get(), 2);
+}
+
+#[pollster::test]
+async fn all_polled_without_option() {
+    let polls = Cell::new(0);
+    let done = Cell::new(false);
+    let waker = Cell::new(None::<Waker>);
+    enjoin::join!(
+        {
+            YieldFor(10).await;
+            done.set(true);
+            waker.take().unwrap().wake();
+        },
+        {
+            CountPolls {
+                polls: &polls,
+                done: &done,
+                waker: &waker,
+            }
+            .await;
+        }
+    );
+    assert!(polls.get() > 2);
+}
+
+#[pollster::test]
+async fn branch_wakers_race() {
+    let res = enjoin::race!(
+        branch_wakers;
+        {
+            YieldFor(5).await;
+            5
+        },
+        {
+            YieldFor(2).await;
+            2
+        }
+    );
+    assert_eq!(res, 2);
+}
+
+#[pollster::test]
+async fn branch_wakers_interleaved() {
+    let mut which = Vec::new();
+    enjoin::join_auto_borrow!(
+        branch_wakers;
+        {
+            for _ in 0..3 {
+                which.push(0);
+                YieldFor(1).await;
+            }
+        },
+        {
+            for _ in 0..3 {
+                which.push(1);
+                YieldFor(2).await;
+            }
+        }
+    );
+    assert_eq!(which, [0, 1, 0, 0, 1, 1]);
+}
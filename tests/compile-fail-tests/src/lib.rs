@@ -84,5 +84,519 @@ async {
     );
 };
 ```
+```compile_fail
+async {
+    let mut v = vec![1];
+    enjoin::join_auto_borrow!(
+        {
+            let it = v.iter_mut();
+            core::future::ready(3).await;
+            it.for_each(|x| *x += 1);
+        },
+        {
+            v.push(2);
+        }
+    );
+};
+```
+```
+async {
+    let mut v = vec![1];
+    enjoin::join_auto_borrow!(
+        {
+            let n = v.len();
+            core::future::ready(3).await;
+            v.push(n);
+        },
+        {
+            v.push(2);
+        }
+    );
+};
+```
+```
+async {
+    let mut v = vec![1];
+    enjoin::join_auto_borrow!(
+        {
+            let first = &mut v[0];
+            *first += 1;
+            core::future::ready(3).await;
+            let first = 0;
+            drop(first);
+        },
+        {
+            v.push(2);
+        }
+    );
+};
+```
+```compile_fail
+async {
+    let mut v = vec![1];
+    enjoin::join_auto_borrow!(
+        {
+            for _ in 0..2 {
+                let r = &mut v;
+                core::future::ready(()).await;
+                r.push(1);
+            }
+        },
+        {
+            v.push(2);
+        }
+    );
+};
+```
 */
 struct _BorrowAcrossYieldPoint;
+
+/**
+```compile_fail
+async {
+    enjoin::join!(
+        x = {
+            3
+        } => x + 1,
+    );
+};
+```
+```
+async {
+    enjoin::race!(
+        x = {
+            3
+        } => x + 1,
+    );
+};
+```
+*/
+struct _ArmsOutsideRace;
+
+/**
+```compile_fail
+async {
+    enjoin::join!(
+        biased;
+        rotate;
+        {},
+        {}
+    );
+};
+```
+```
+async {
+    enjoin::join!(
+        rotate;
+        {},
+        {}
+    );
+};
+```
+*/
+struct _ConflictingPollOrder;
+
+/**
+```compile_fail
+async {
+    enjoin::join!(
+        limit = 2;
+        {},
+        {}
+    );
+};
+```
+```
+async {
+    enjoin::join_for!(limit = 2; for _i in 0..3 {});
+};
+```
+*/
+struct _LimitOutsideJoinFor;
+
+/**
+```compile_fail
+async {
+    let mut v = vec![1];
+    enjoin::join_auto_borrow!(
+        copy(v);
+        {
+            v.push(2);
+        },
+        {
+            v = vec![3];
+        }
+    );
+};
+```
+```compile_fail
+async {
+    let mut a = 1;
+    let mut b = 1;
+    enjoin::join_auto_borrow!(
+        copy(b);
+        {
+            a += 1;
+        },
+        {
+            a += 1;
+            b += 1;
+        }
+    );
+};
+```
+```compile_fail
+async {
+    let mut a = 1;
+    enjoin::join!(
+        copy(a);
+        {
+            a += 1;
+        },
+        {}
+    );
+};
+```
+```
+async {
+    let mut a = 1;
+    let mut b = 1;
+    enjoin::join_auto_borrow!(
+        copy(b);
+        {
+            a += 1;
+            b = a;
+        },
+        {
+            a += 1;
+            b += a;
+        }
+    );
+};
+```
+*/
+struct _CopyCapture;
+
+/**
+```compile_fail
+async {
+    let mut a = 1;
+    let mut b = 1;
+    enjoin::join_auto_borrow!(
+        share(mut a, mut b),
+        {
+            a += 1;
+        },
+        {
+            a += 1;
+        }
+    );
+};
+```
+```compile_fail
+async {
+    let mut a = 1;
+    enjoin::join!(
+        share(mut a),
+        {
+            a += 1;
+        },
+        {}
+    );
+};
+```
+```
+async {
+    let mut a = 1;
+    enjoin::join_auto_borrow!(
+        share(mut a),
+        {
+            a += 1;
+        },
+        {
+            a += 1;
+        }
+    );
+};
+```
+*/
+struct _ShareList;
+
+/**
+```compile_fail
+#![deny(deprecated)]
+async {
+    let mut a = 1;
+    enjoin::join_auto_borrow!(
+        explain;
+        {
+            a += 1;
+        },
+        {
+            a += 1;
+        }
+    );
+};
+```
+```compile_fail
+async {
+    let mut a = 1;
+    enjoin::join!(
+        explain;
+        {
+            a += 1;
+        },
+        {}
+    );
+};
+```
+```
+#![deny(deprecated)]
+async {
+    let mut a = 1;
+    enjoin::join_auto_borrow!(
+        {
+            a += 1;
+        },
+        {
+            a += 1;
+        }
+    );
+};
+```
+*/
+struct _Explain;
+
+/**
+```compile_fail
+async {
+    let mut a = 1;
+    enjoin::join!(
+        ref_methods(peek);
+        {
+            a += 1;
+        },
+        {}
+    );
+};
+```
+*/
+struct _RefMethodsOutsideAutoBorrow;
+
+/**
+```compile_fail
+async {
+    enjoin::race!(a = { 1 }, b = { 2 });
+};
+```
+```compile_fail
+async {
+    enjoin::join!(a = { 1 }, { 2 });
+};
+```
+```compile_fail
+async {
+    enjoin::join!(a = { 1 }, a = { 2 });
+};
+```
+```compile_fail
+async {
+    enjoin::join!(mut a = { 1 }, b = { 2 });
+};
+```
+```
+async {
+    let res = enjoin::join!(a = { 1 }, b = { 2 });
+    let _sum: i32 = res.a + res.b;
+};
+```
+*/
+struct _NamedBlocks;
+
+/**
+```compile_fail
+async {
+    enjoin::race!(
+        on_escape = |_| {};
+        { 1 },
+        { 2 }
+    );
+};
+```
+```compile_fail
+async {
+    enjoin::join_for!(on_escape = |_| {}; for i in 0..3 { i });
+};
+```
+```compile_fail
+async {
+    enjoin::join!(
+        on_escape = |_| {};
+        on_escape = |_| {};
+        { 1 },
+        { 2 }
+    );
+};
+```
+```
+async {
+    enjoin::try_race!(
+        on_escape = |_| {};
+        { Ok::<u8, ()>(1) },
+        { Ok::<u8, ()>(2) }
+    );
+};
+```
+*/
+struct _OnEscape;
+
+/**
+```compile_fail
+async {
+    enjoin::join!(
+        {} on_cancel {
+            core::future::ready(1).await;
+        },
+        {}
+    );
+};
+```
+```compile_fail
+async {
+    'a: loop {
+        enjoin::join!(
+            {} on_cancel {
+                break 'a;
+            },
+            {}
+        );
+    }
+};
+```
+```compile_fail
+async fn f() -> Option<u8> {
+    enjoin::join!(
+        {} on_cancel {
+            None?;
+        },
+        {}
+    );
+    Some(1)
+}
+```
+```compile_fail
+async {
+    let mut a = 1;
+    enjoin::join!(
+        {
+            a += 1;
+        } on_cancel {
+            a += 1;
+        },
+        {}
+    );
+};
+```
+```
+async {
+    let mut a = 1;
+    enjoin::join_auto_borrow!(
+        {
+            a += 1;
+        } on_cancel {
+            'b: loop {
+                a += 1;
+                break 'b;
+            }
+        },
+        {}
+    );
+};
+```
+*/
+struct _OnCancel;
+
+/**
+```compile_fail
+async {
+    enjoin::join_for!(graceful(signal, 2); for i in 0..3 { i });
+};
+```
+```compile_fail
+async {
+    enjoin::race!(
+        graceful(signal, 2);
+        graceful(signal, 3);
+        { 1 },
+        { 2 }
+    );
+};
+```
+```compile_fail
+async {
+    enjoin::race!(
+        graceful(2);
+        { 1 },
+        { 2 }
+    );
+};
+```
+```
+async {
+    let mut count = 0;
+    enjoin::join_auto_borrow!(
+        graceful(signal, 2);
+        {
+            count += 1;
+            signal.cancelled().await;
+        },
+        {
+            count += 1;
+            signal.cancelled().await;
+        }
+    );
+};
+```
+*/
+struct _Graceful;
+
+/**
+```compile_fail
+async {
+    enjoin::race!(
+        { 1 },
+        timeout(core::time::Duration::from_secs(1), &()) => 0,
+    );
+};
+```
+```compile_fail
+struct T;
+impl enjoin::Timer for T {
+    fn sleep(&self, _: core::time::Duration) -> impl core::future::Future<Output = ()> {
+        core::future::ready(())
+    }
+}
+async {
+    enjoin::race!(
+        { 1 },
+        timeout(core::time::Duration::from_secs(1), T) => 0,
+        timeout(core::time::Duration::from_secs(2), T) => 0,
+    );
+};
+```
+```
+struct T;
+impl enjoin::Timer for T {
+    fn sleep(&self, _: core::time::Duration) -> impl core::future::Future<Output = ()> {
+        core::future::ready(())
+    }
+}
+async {
+    let _: u8 = enjoin::race!(
+        { 1 },
+        timeout(core::time::Duration::from_secs(1), T) => 0,
+    );
+};
+```
+*/
+struct _Timeout;
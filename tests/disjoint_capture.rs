@@ -1,3 +1,5 @@
+#![allow(dropping_references)]
+
 mod utils;
 use utils::YieldFor;
 
@@ -0,0 +1,77 @@
+//! Building this crate checks that the macros handle syntax only available
+//! in the 2024 edition.
+//! Each function would fail to compile if a binding were mistaken for a
+//! capture of the immutable `x`, since captures used mutably get borrowed mutably.
+
+#![allow(dropping_copy_types)]
+
+pub async fn if_let_chain_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            if let Some(mut x) = Some(1i32)
+                && let Some(y) = x.checked_add(1)
+                && y > 1
+            {
+                x += y;
+                drop(x);
+            }
+        },
+        {
+            if let Some(mut x) = Some(1i32)
+                && x > 0
+            {
+                x += 1;
+                drop(x);
+            }
+        }
+    );
+    drop(x);
+}
+
+pub async fn while_let_chain_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            let mut it = 0i32..3;
+            while let Some(mut x) = it.next()
+                && x < 2
+            {
+                x += 1;
+                drop(x);
+            }
+        },
+        {
+            let mut it = 0i32..3;
+            while let Some(mut x) = it.next()
+                && let Some(mut y) = x.checked_sub(1)
+            {
+                x += 1;
+                y += x;
+                drop(y);
+            }
+        }
+    );
+    drop(x);
+}
+
+pub async fn if_let_chain_not_shadowed() -> i32 {
+    let mut x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            if let Some(y) = Some(1)
+                && y > 0
+            {
+                x += y;
+            }
+        },
+        {
+            if let Some(y) = Some(2i32)
+                && let Some(z) = y.checked_add(1)
+            {
+                x += z;
+            }
+        }
+    );
+    x
+}
@@ -0,0 +1,64 @@
+// `explain;` reports through deprecation warnings.
+#![allow(deprecated, clippy::needless_borrow)]
+
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn explain_keeps_behavior() {
+    let mut count = 0;
+    let mut log = Vec::new();
+    let name = "x".to_string();
+    let mut only = 0;
+    enjoin::join_auto_borrow!(
+        explain;
+        {
+            count += 1;
+            only += 1;
+            log.push((&name).len());
+            YieldFor(1).await;
+            log.push(0);
+        },
+        {
+            count += 1;
+            log.push((&name).len());
+        }
+    );
+    assert_eq!(count, 2);
+    assert_eq!(only, 1);
+    assert_eq!(log, [1, 1, 0]);
+}
+
+#[pollster::test]
+async fn explain_without_shared() {
+    let mut a = 0;
+    let mut b = 0;
+    enjoin::join_auto_borrow!(
+        explain;
+        {
+            a += 1;
+        },
+        {
+            b += 1;
+        }
+    );
+    assert_eq!((a, b), (1, 1));
+}
+
+#[pollster::test]
+async fn explain_in_join_for() {
+    let mut total = 0;
+    enjoin::join_for_auto_borrow!(
+        explain;
+        for i in 0..3 {
+            total += i;
+        }
+    );
+    assert_eq!(total, 3);
+}
+
+#[test]
+fn explain_notes() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/explain_*.rs");
+}
@@ -0,0 +1,176 @@
+#![allow(clippy::never_loop)]
+
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn race_lets_the_losers_wind_down() {
+    let mut log = Vec::new();
+    let winner = enjoin::race!(
+        graceful(signal, 3);
+        {
+            YieldFor(1).await;
+            "fast"
+        },
+        {
+            signal.cancelled().await;
+            log.push("wound down");
+            "slow"
+        }
+    );
+    assert_eq!(winner, "fast");
+    assert_eq!(log, ["wound down"]);
+}
+
+#[pollster::test]
+async fn extra_polls_are_bounded() {
+    let mut polls = 0;
+    let winner = enjoin::race!(
+        graceful(signal, 2);
+        { 1 },
+        {
+            loop {
+                polls += 1;
+                YieldFor(1).await;
+            }
+        }
+    );
+    assert_eq!(winner, 1);
+    // The first block won before the second one was ever polled,
+    // so the second one only got the two extra polls.
+    assert_eq!(polls, 2);
+}
+
+#[pollster::test]
+async fn loser_that_never_wakes() {
+    let winner = enjoin::race!(
+        graceful(signal, 2);
+        { 1 },
+        {
+            core::future::pending::<()>().await;
+            2
+        }
+    );
+    assert_eq!(winner, 1);
+}
+
+#[pollster::test]
+async fn escape_waits_for_wind_down() {
+    let mut log = Vec::new();
+    let mut partial = None;
+    'outer: loop {
+        enjoin::join_auto_borrow!(
+            graceful(signal, 5);
+            on_escape = |p| partial = Some(p.0);
+            {
+                YieldFor(1).await;
+                assert!(!signal.is_cancelled());
+                log.push("escaping");
+                break 'outer;
+            },
+            {
+                while !signal.is_cancelled() {
+                    YieldFor(1).await;
+                }
+                log.push("saving");
+                YieldFor(1).await;
+                log.push("saved");
+                "saved"
+            }
+        );
+        unreachable!();
+    }
+    assert_eq!(log, ["escaping", "saving", "saved"]);
+    assert_eq!(partial, Some((None, Some("saved"))));
+}
+
+#[pollster::test]
+async fn no_wind_down_when_all_finished() {
+    let res = enjoin::join!(
+        graceful(signal, 5);
+        {
+            YieldFor(1).await;
+            signal.is_cancelled()
+        },
+        { signal.is_cancelled() }
+    );
+    assert_eq!(res, (false, false));
+}
+
+#[pollster::test]
+async fn try_join_failure() {
+    let mut cleaned_up = false;
+    let res = enjoin::try_join!(
+        graceful(signal, 2);
+        {
+            YieldFor(1).await;
+            Err::<(), _>("failed")
+        },
+        {
+            signal.cancelled().await;
+            cleaned_up = true;
+            Ok(())
+        }
+    );
+    assert_eq!(res, Err("failed"));
+    assert!(cleaned_up);
+}
+
+#[pollster::test]
+async fn with_on_cancel() {
+    let mut b_cancelled = false;
+    let mut c_cancelled = false;
+    enjoin::race!(
+        graceful(signal, 1);
+        {
+            YieldFor(1).await;
+        },
+        {
+            // Ignores the signal, so it is dropped after the extra poll.
+            YieldFor(10).await;
+        } on_cancel {
+            b_cancelled = true;
+        },
+        {
+            signal.cancelled().await;
+        } on_cancel {
+            c_cancelled = true;
+        }
+    );
+    assert!(b_cancelled);
+    assert!(!c_cancelled);
+}
+
+#[pollster::test]
+async fn on_cancel_of_block_started_while_winding_down() {
+    let mut cancelled = false;
+    let winner = enjoin::race!(
+        graceful(signal, 2);
+        { 1 },
+        {
+            loop {
+                YieldFor(1).await;
+            }
+        } on_cancel {
+            cancelled = true;
+        }
+    );
+    assert_eq!(winner, 1);
+    assert!(cancelled);
+}
+
+#[test]
+fn signal_is_send() {
+    fn assert_send<T: Send>(_: T) {}
+    assert_send(async {
+        enjoin::race!(
+            graceful(signal, 1);
+            {
+                YieldFor(1).await;
+            },
+            {
+                signal.cancelled().await;
+            }
+        )
+    });
+}
@@ -1,3 +1,5 @@
+#![allow(dropping_references, clippy::needless_borrow)]
+
 #[pollster::test]
 async fn ignore_immut_reference() {
     let count = 0i32;
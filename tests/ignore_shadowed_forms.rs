@@ -0,0 +1,183 @@
+#![allow(
+    dropping_copy_types,
+    clippy::single_match,
+    clippy::while_let_on_iterator
+)]
+
+// Stands in for `futures::pin_mut!`, which shadows its argument.
+macro_rules! pin_mut {
+    ($x:ident) => {
+        let mut $x = $x;
+    };
+}
+
+#[pollster::test]
+async fn let_else_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            let Some(mut x) = Some(4) else {
+                return;
+            };
+            x += 1;
+            assert_eq!(x, 5);
+        },
+        {
+            let Some(mut x) = Some(13) else {
+                return;
+            };
+            x += 1;
+            assert_eq!(x, 14);
+        }
+    );
+    assert_eq!(x, 3);
+}
+
+#[pollster::test]
+async fn let_else_not_shadowed_in_else() {
+    let mut x = 3;
+    for _ in 0..1 {
+        enjoin::join_auto_borrow!(
+            {
+                let Some(y) = None::<i32> else {
+                    x += 1;
+                    continue;
+                };
+                drop(y);
+            },
+            {
+                x += 1;
+            }
+        );
+    }
+    assert_eq!(x, 4);
+}
+
+#[pollster::test]
+async fn while_let_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            let mut it = 0..2;
+            while let Some(mut x) = it.next() {
+                x += 1;
+                drop(x);
+            }
+        },
+        {
+            let mut it = 0..2;
+            while let Some(mut x) = it.next() {
+                x += 1;
+                drop(x);
+            }
+        }
+    );
+    assert_eq!(x, 3);
+}
+
+#[pollster::test]
+async fn match_guard_uses_binding() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            match Some(5) {
+                Some(mut x) if x > 1 => {
+                    x += 1;
+                    drop(x);
+                }
+                _ => {}
+            }
+        },
+        {
+            match Some(5) {
+                Some(mut x) if x < 10 => {
+                    x += 1;
+                    drop(x);
+                }
+                _ => {}
+            }
+        }
+    );
+    assert_eq!(x, 3);
+}
+
+#[pollster::test]
+async fn closure_param_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            let f = |mut x: i32| {
+                x += 1;
+                x
+            };
+            assert_eq!(f(1), 2);
+        },
+        {
+            let f = |(mut x, y): (i32, i32)| {
+                x += y;
+                x
+            };
+            assert_eq!(f((1, 2)), 3);
+        }
+    );
+    assert_eq!(x, 3);
+}
+
+#[pollster::test]
+async fn async_closure_param_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            let f = async |mut x: i32| {
+                x += 1;
+                x
+            };
+            assert_eq!(f(1).await, 2);
+        },
+        {
+            let f = async move |mut x: i32| {
+                x += 2;
+                x
+            };
+            assert_eq!(f(1).await, 3);
+        }
+    );
+    assert_eq!(x, 3);
+}
+
+#[pollster::test]
+async fn binding_at_subpattern_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            if let mut x @ 1..=9 = 5 {
+                x += 1;
+                drop(x);
+            }
+        },
+        {
+            let (a, mut x) = (1, 2);
+            x += a;
+            drop(x);
+        }
+    );
+    assert_eq!(x, 3);
+}
+
+#[pollster::test]
+async fn macro_shadowed() {
+    let x = 3;
+    enjoin::join_auto_borrow!(
+        {
+            pin_mut!(x);
+            x += 1;
+            assert_eq!(x, 4);
+        },
+        {
+            pin_mut!(x);
+            x += 2;
+            assert_eq!(x, 5);
+        }
+    );
+    assert_eq!(x, 3);
+}
@@ -1,3 +1,5 @@
+#![allow(dropping_copy_types, clippy::single_match)]
+
 #[pollster::test]
 async fn let_shadowed() {
     let x = 3;
@@ -0,0 +1,119 @@
+#![allow(clippy::useless_vec)]
+
+mod utils;
+use utils::YieldFor;
+
+struct Buffers {
+    bufs: Vec<Vec<u8>>,
+    count: usize,
+}
+
+#[pollster::test]
+async fn index_assign_in_two_blocks() {
+    let mut buf = [0; 4];
+    enjoin::join_auto_borrow!(
+        {
+            buf[0] += 1;
+            YieldFor(1).await;
+            buf[1] = 5;
+        },
+        {
+            let i = 2;
+            buf[i] += 3;
+            YieldFor(1).await;
+            buf[i + 1] = buf[0];
+        }
+    );
+    assert_eq!(buf, [1, 5, 3, 1]);
+}
+
+#[pollster::test]
+async fn index_read_only_is_not_shared() {
+    let buf = vec![1, 2, 3];
+    let (a, b) = enjoin::join_auto_borrow!(
+        {
+            // `buf` isn't in a cell, so this can be held across the await.
+            let first = &buf[0];
+            YieldFor(1).await;
+            *first
+        },
+        { buf[1] + buf[2] }
+    );
+    assert_eq!((a, b), (1, 5));
+}
+
+#[pollster::test]
+async fn index_under_fields() {
+    let mut b = Buffers {
+        bufs: vec![vec![1], vec![2, 3]],
+        count: 0,
+    };
+    enjoin::join_auto_borrow!(
+        {
+            b.bufs[0].push(4);
+            YieldFor(1).await;
+            b.count += b.bufs[1].len();
+        },
+        {
+            b.bufs[1][0] = 9;
+            b.count += 1;
+        }
+    );
+    assert_eq!(b.bufs, [vec![1, 4], vec![9, 3]]);
+    assert_eq!(b.count, 3);
+}
+
+#[pollster::test]
+async fn disjoint_tuple_fields_with_index() {
+    let mut pair = (vec![1, 0], vec![0, 0]);
+    enjoin::join_auto_borrow!(
+        {
+            pair.0[1] += 1;
+            YieldFor(1).await;
+            pair.0[0] += 1;
+        },
+        {
+            pair.1[0] += 1;
+            YieldFor(1).await;
+            pair.1[1] += 1;
+        }
+    );
+    assert_eq!(pair, (vec![2, 1], vec![1, 1]));
+}
+
+#[pollster::test]
+async fn index_with_captured_index() {
+    let mut buf = [0; 3];
+    let mut i = 0;
+    enjoin::join_auto_borrow!(
+        {
+            buf[i] += 1;
+            YieldFor(1).await;
+            i += 1;
+        },
+        {
+            YieldFor(2).await;
+            buf[i] += 10;
+        }
+    );
+    assert_eq!(buf, [1, 10, 0]);
+}
+
+#[pollster::test]
+async fn slices() {
+    let mut v = vec![0; 4];
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            v[..2].fill(1);
+            YieldFor(1).await;
+            log.push(v[2..].iter().sum::<i32>());
+        },
+        {
+            v[2..].fill(2);
+            log.push(v[..].iter().sum::<i32>());
+        }
+    );
+    assert_eq!(v, [1, 1, 2, 2]);
+    assert_eq!(log, [6, 4]);
+}
@@ -0,0 +1,182 @@
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn join_for_results_in_order() {
+    let res = enjoin::join_for!(for i in 0..4usize {
+        YieldFor(4 - i).await;
+        i * 10
+    });
+    assert_eq!(res, [0, 10, 20, 30]);
+}
+
+#[pollster::test]
+async fn join_for_interleaved() {
+    let mut log = Vec::new();
+    enjoin::join_for_auto_borrow!(for name in ["a", "b"] {
+        log.push(name);
+        YieldFor(1).await;
+        log.push(name);
+    });
+    assert_eq!(log, ["a", "b", "a", "b"]);
+}
+
+#[pollster::test]
+async fn join_for_empty() {
+    let res: Vec<()> = enjoin::join_for!(for _x in Vec::<i32>::new() {
+        YieldFor(1).await;
+    });
+    assert!(res.is_empty());
+}
+
+#[pollster::test]
+async fn join_for_pattern() {
+    let pairs = vec![(1, String::from("one")), (2, String::from("two"))];
+    let res = enjoin::join_for!(for (n, s) in pairs {
+        YieldFor(n).await;
+        format!("{n}:{s}")
+    });
+    assert_eq!(res, ["1:one", "2:two"]);
+}
+
+#[pollster::test]
+async fn join_for_shared_borrow() {
+    let names = [String::from("x"), String::from("yy")];
+    let res = enjoin::join_for!(for i in 0..2 {
+        YieldFor(1).await;
+        names[i].len()
+    });
+    assert_eq!(res, [1, 2]);
+}
+
+#[pollster::test]
+async fn join_for_break() {
+    let mut reached = false;
+    let res = loop {
+        enjoin::join_for!(for i in 0..3 {
+            YieldFor(i).await;
+            if i == 1 {
+                break i;
+            }
+            YieldFor(10).await;
+        });
+        reached = true;
+    };
+    assert_eq!(res, 1);
+    assert!(!reached);
+}
+
+#[pollster::test]
+async fn join_for_continue() {
+    let mut total = 0;
+    for round in 0..3 {
+        let res = enjoin::join_for!(for i in 0..3 {
+            if i == round {
+                continue;
+            }
+            YieldFor(1).await;
+            i
+        });
+        total += res.iter().sum::<i32>();
+    }
+    assert_eq!(total, 0);
+}
+
+#[pollster::test]
+async fn join_for_return_and_try() {
+    async fn inner(fail_at: i32) -> Result<Vec<i32>, i32> {
+        let res = enjoin::join_for!(for i in 0..3 {
+            YieldFor(1).await;
+            if i == fail_at {
+                Err(i)?;
+            }
+            if i == 10 {
+                return Ok(Vec::new());
+            }
+            i
+        });
+        Ok(res)
+    }
+    assert_eq!(inner(1).await, Err(1));
+    assert_eq!(inner(5).await, Ok(vec![0, 1, 2]));
+}
+
+#[pollster::test]
+async fn join_for_options() {
+    let res = enjoin::join_for!(branch_wakers; rotate; for i in 0..3usize {
+        YieldFor(3 - i).await;
+        i
+    });
+    assert_eq!(res, [0, 1, 2]);
+}
+
+#[pollster::test]
+async fn join_for_limit() {
+    let mut running = 0;
+    let mut max_running = 0;
+    let res = enjoin::join_for_auto_borrow!(limit = 2; for i in 0..5usize {
+        running += 1;
+        max_running = max_running.max(running);
+        YieldFor(5 - i).await;
+        running -= 1;
+        i * 10
+    });
+    assert_eq!(res, [0, 10, 20, 30, 40]);
+    assert_eq!(max_running, 2);
+}
+
+#[pollster::test]
+async fn join_for_limit_starts_next_when_one_finishes() {
+    let mut log = Vec::new();
+    enjoin::join_for_auto_borrow!(limit = 2; for (i, wait) in [(0, 4), (1, 1), (2, 1)] {
+        log.push(("start", i));
+        YieldFor(wait).await;
+        log.push(("end", i));
+    });
+    assert_eq!(
+        log,
+        [
+            ("start", 0),
+            ("start", 1),
+            ("end", 1),
+            ("start", 2),
+            ("end", 2),
+            ("end", 0)
+        ]
+    );
+}
+
+#[pollster::test]
+async fn join_for_limit_escape() {
+    let mut started = Vec::new();
+    let res = loop {
+        enjoin::join_for_auto_borrow!(limit = 2; for i in 0..10 {
+            started.push(i);
+            YieldFor(1).await;
+            if i == 3 {
+                break i;
+            }
+        });
+    };
+    assert_eq!(res, 3);
+    // 4 was started when 2 finished, in the same poll as 3 breaking.
+    assert_eq!(started, [0, 1, 2, 3, 4]);
+}
+
+#[pollster::test]
+async fn join_for_limit_options() {
+    let res = enjoin::join_for!(limit = 3; branch_wakers; rotate; for i in 0..7usize {
+        YieldFor(i % 3).await;
+        i
+    });
+    assert_eq!(res, [0, 1, 2, 3, 4, 5, 6]);
+}
+
+#[pollster::test]
+async fn join_for_limit_more_than_items() {
+    let res = enjoin::join_for!(limit = 10; for i in 0..3 {
+        YieldFor(1).await;
+        i
+    });
+    assert_eq!(res, [0, 1, 2]);
+}
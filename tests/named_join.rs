@@ -0,0 +1,86 @@
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn named_join() {
+    let res = enjoin::join!(
+        user = {
+            YieldFor(2).await;
+            "alice"
+        },
+        count = {
+            YieldFor(1).await;
+            3
+        },
+        done = {}
+    );
+    assert_eq!(res.user, "alice");
+    assert_eq!(res.count, 3);
+    assert_eq!(res.done, ());
+}
+
+#[pollster::test]
+async fn named_join_destructure() {
+    let out = enjoin::join!(a = { 1 }, b = { "two" },);
+    let copied = out;
+    assert_eq!(out, copied);
+    assert!(format!("{:?}", out).ends_with(r#"{ a: 1, b: "two" }"#));
+}
+
+#[pollster::test]
+async fn named_try_join() {
+    let ok = enjoin::try_join!(
+        a = {
+            YieldFor(1).await;
+            Ok::<_, &str>(1)
+        },
+        b = { Ok::<_, &str>(2) }
+    );
+    let ok = ok.unwrap();
+    assert_eq!((ok.a, ok.b), (1, 2));
+
+    let err = enjoin::try_join!(
+        a = {
+            YieldFor(1).await;
+            Ok::<_, &str>(1)
+        },
+        b = { Err::<u8, _>("no") }
+    );
+    assert_eq!(err.map(|r| r.a), Err("no"));
+}
+
+#[pollster::test]
+async fn named_join_escape() {
+    let mut reached = false;
+    'outer: for i in 0..3 {
+        let res = enjoin::join!(
+            first = {
+                if i == 1 {
+                    break 'outer;
+                }
+                i
+            },
+            second = { i * 10 }
+        );
+        assert_eq!(res.second, res.first * 10);
+        reached = i == 0;
+    }
+    assert!(reached);
+}
+
+#[pollster::test]
+async fn named_join_auto_borrow() {
+    let mut log = Vec::new();
+    let res = enjoin::join_auto_borrow!(
+        a = {
+            log.push(1);
+            YieldFor(1).await;
+            log.len()
+        },
+        b = {
+            log.push(2);
+            "b"
+        }
+    );
+    assert_eq!((res.a, res.b), (2, "b"));
+}
@@ -0,0 +1,117 @@
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn nested_async_block_awaited_inline() {
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            async {
+                log.push("a1");
+                YieldFor(2).await;
+                log.push("a2");
+            }
+            .await;
+        },
+        {
+            log.push("b1");
+            YieldFor(1).await;
+            log.push("b2");
+        }
+    );
+    assert_eq!(log, ["a1", "b1", "b2", "a2"]);
+}
+
+#[pollster::test]
+async fn nested_async_move_block() {
+    let mut count = 0;
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            let fut = async move {
+                log.push(1);
+                YieldFor(1).await;
+                count += 1;
+                log.push(2);
+            };
+            fut.await;
+        },
+        {
+            log.push(3);
+            count += 10;
+        }
+    );
+    assert_eq!(count, 11);
+    assert_eq!(log, [1, 3, 2]);
+}
+
+#[pollster::test]
+async fn closure_using_shared() {
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            let record = |x| log.push(x);
+            record(1);
+            YieldFor(1).await;
+            record(3);
+        },
+        {
+            log.push(2);
+        }
+    );
+    assert_eq!(log, [1, 2, 3]);
+}
+
+#[pollster::test]
+async fn async_closure_using_shared() {
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            let record = async |x| {
+                log.push(x);
+                YieldFor(1).await;
+                log.push(x + 10);
+            };
+            record(1).await;
+        },
+        {
+            log.push(2);
+        }
+    );
+    assert_eq!(log, [1, 2, 11]);
+}
+
+#[pollster::test]
+async fn closures_in_iterator_adapters() {
+    let mut total = 0;
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            [1, 2, 3].iter().for_each(|x| total += x);
+            YieldFor(1).await;
+            log.push(total);
+        },
+        {
+            total += 10;
+            log.push(total);
+        }
+    );
+    assert_eq!(log, [16, 16]);
+}
+
+#[pollster::test]
+async fn inline_closure_uses_statement_borrow() {
+    let mut v = vec![1, 2];
+    let mut n = 0;
+    enjoin::join_auto_borrow!(
+        {
+            n = v.iter().filter(|x| v.contains(x)).count();
+            YieldFor(1).await;
+        },
+        {
+            v.push(3);
+        }
+    );
+    assert_eq!(n, 2);
+    assert_eq!(v, [1, 2, 3]);
+}
@@ -0,0 +1,192 @@
+mod utils;
+use utils::YieldFor;
+
+macro_rules! then {
+    ($first:expr, $e:expr) => {{
+        $first;
+        $e
+    }};
+}
+
+#[pollster::test]
+async fn nested_join() {
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            log.push("a1");
+            enjoin::join!(
+                {
+                    log.push("inner1");
+                    YieldFor(2).await;
+                    log.push("inner3");
+                },
+                {
+                    YieldFor(1).await;
+                    log.push("inner2");
+                }
+            );
+            log.push("a2");
+        },
+        {
+            YieldFor(1).await;
+            log.push("b");
+        }
+    );
+    assert_eq!(log, ["a1", "inner1", "inner2", "b", "inner3", "a2"]);
+}
+
+#[pollster::test]
+async fn nested_join_in_expression() {
+    let mut total = 0;
+    let (x, _) = enjoin::join_auto_borrow!(
+        {
+            let (a, b) = enjoin::join!(
+                {
+                    YieldFor(1).await;
+                    total += 1;
+                    1
+                },
+                {
+                    total += 10;
+                    2
+                }
+            );
+            a + b
+        },
+        {
+            YieldFor(1).await;
+            total += 100;
+        }
+    );
+    assert_eq!(x, 3);
+    assert_eq!(total, 111);
+}
+
+#[pollster::test]
+async fn nested_race() {
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            let first = enjoin::race!(
+                {
+                    YieldFor(3).await;
+                    log.push("slow");
+                    "slow"
+                },
+                {
+                    YieldFor(1).await;
+                    log.push("fast");
+                    "fast"
+                }
+            );
+            log.push(first);
+        },
+        {
+            log.push("b");
+        }
+    );
+    assert_eq!(log, ["b", "fast", "fast"]);
+}
+
+#[pollster::test]
+async fn await_in_unknown_macro() {
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            log.push("a1");
+            if then!(YieldFor(2).await, true) {
+                log.push("a3");
+            }
+        },
+        {
+            log.push("b");
+        }
+    );
+    assert_eq!(log, ["a1", "b", "a3"]);
+}
+
+#[pollster::test]
+async fn captures_in_formatting_macros() {
+    let mut count = 0;
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            count += 1;
+            log.push(format!("a {}", count));
+            YieldFor(1).await;
+        },
+        {
+            count += 1;
+            log.push(format!("b {}", count));
+            assert_eq!(log.len(), 2);
+        }
+    );
+    assert_eq!(log, ["a 1", "b 2"]);
+}
+
+#[pollster::test]
+async fn try_in_formatting_macro() {
+    async fn inner() -> Result<String, String> {
+        enjoin::join!(
+            {
+                let s = format!("{}", Err::<u8, _>("oops".to_string())?);
+                YieldFor(1).await;
+                s
+            },
+            {}
+        );
+        panic!("NO");
+    }
+    assert_eq!(inner().await, Err("oops".to_string()));
+}
+
+#[pollster::test]
+async fn named_format_arguments() {
+    let mut count = 1;
+    let (a, b) = enjoin::join_auto_borrow!(
+        {
+            println!("{n}", n = 1);
+            count += 1;
+            format!("{n} {}", count, n = 1)
+        },
+        {
+            println!("{n}", n = 1);
+            format!("{n}", n = count + 1)
+        }
+    );
+    assert_eq!(a, "1 2");
+    assert_eq!(b, "3");
+}
+
+enum Shape {
+    Square { side: u32 },
+    Circle,
+}
+
+#[pollster::test]
+async fn only_blocks_of_nested_macros_are_exposed() {
+    let mut side = 0;
+    let mut areas = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            // The braces of the pattern aren't a block, so `side` here isn't the shared one.
+            let area = enjoin::race!(
+                Shape::Square { side } = {
+                    YieldFor(1).await;
+                    Shape::Square { side: 3 }
+                } => side * side,
+                Shape::Circle = {
+                    YieldFor(2).await;
+                    Shape::Circle
+                } => 0,
+            );
+            areas.push(area);
+            side += 1;
+        },
+        {
+            side += 1;
+        }
+    );
+    assert_eq!(areas, [9]);
+    assert_eq!(side, 2);
+}
@@ -0,0 +1,35 @@
+//! Building this crate checks that the code the macros generate
+//! only needs `core`.
+
+#![no_std]
+
+pub async fn join() -> (i32, i32) {
+    enjoin::join!({ 1 }, { 2 })
+}
+
+pub async fn join_auto_borrow() -> i32 {
+    let mut count = 0;
+    enjoin::join_auto_borrow!(
+        {
+            count += 1;
+            core::future::ready(()).await;
+            count += 1;
+        },
+        {
+            count += 1;
+        }
+    );
+    count
+}
+
+pub async fn race_and_try(x: Option<i32>) -> Option<i32> {
+    let res = enjoin::race!(
+        rotate;
+        {
+            core::future::pending::<()>().await;
+            0
+        },
+        { x? }
+    );
+    enjoin::try_join!({ Some(res) }, { x }).map(|(a, b)| a + b)
+}
@@ -0,0 +1,129 @@
+#![allow(clippy::never_loop)]
+
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn race_cancels_the_losers() {
+    let mut fast_cancelled = false;
+    let mut slow_cancelled = false;
+    let winner = enjoin::race!(
+        {
+            YieldFor(1).await;
+            "fast"
+        } on_cancel {
+            fast_cancelled = true;
+        },
+        {
+            YieldFor(3).await;
+            "slow"
+        } on_cancel {
+            slow_cancelled = true;
+        }
+    );
+    assert_eq!(winner, "fast");
+    assert!(!fast_cancelled);
+    assert!(slow_cancelled);
+}
+
+#[pollster::test]
+async fn not_run_when_finished() {
+    let mut cancelled = 0;
+    let res = enjoin::join!(
+        { 1 } on_cancel { cancelled += 1; },
+        {
+            YieldFor(2).await;
+            2
+        }
+    );
+    assert_eq!(res, (1, 2));
+    assert_eq!(cancelled, 0);
+}
+
+#[pollster::test]
+async fn escape_cancels_the_running_blocks() {
+    let mut log = Vec::new();
+    'outer: loop {
+        enjoin::join_auto_borrow!(
+            {
+                log.push("a finished");
+            } on_cancel {
+                log.push("a cancelled");
+            },
+            {
+                YieldFor(1).await;
+                log.push("b escaping");
+                break 'outer;
+            } on_cancel {
+                log.push("b cancelled");
+            },
+            {
+                YieldFor(5).await;
+            } on_cancel {
+                log.push("c cancelled");
+            }
+        );
+    }
+    assert_eq!(log, ["a finished", "b escaping", "c cancelled"]);
+}
+
+#[pollster::test]
+async fn not_run_when_never_started() {
+    let mut log = Vec::new();
+    'outer: loop {
+        enjoin::join_auto_borrow!(
+            biased;
+            {
+                break 'outer;
+            },
+            {
+                log.push("b started");
+                YieldFor(1).await;
+            } on_cancel {
+                log.push("b cancelled");
+            }
+        );
+    }
+    assert!(log.is_empty());
+}
+
+#[pollster::test]
+async fn runs_before_on_escape() {
+    let mut log = Vec::new();
+    'outer: loop {
+        enjoin::join_auto_borrow!(
+            on_escape = |_| log.push("escaped");
+            {
+                YieldFor(3).await;
+            } on_cancel {
+                log.push("cancelled");
+            },
+            {
+                YieldFor(1).await;
+                break 'outer;
+            }
+        );
+    }
+    assert_eq!(log, ["cancelled", "escaped"]);
+}
+
+#[pollster::test]
+async fn loops_inside() {
+    let mut total = 0;
+    enjoin::race!(
+        {
+            YieldFor(1).await;
+        },
+        {
+            YieldFor(3).await;
+        } on_cancel {
+            for i in 0..10 {
+                if i == 3 {
+                    break;
+                }
+                total += i;
+            }
+        }
+    );
+    assert_eq!(total, 3);
+}
@@ -0,0 +1,105 @@
+#![allow(clippy::never_loop)]
+
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn break_keeps_finished_outputs() {
+    let mut recorded = Vec::new();
+    'outer: loop {
+        enjoin::join!(
+            on_escape = |partial| {
+                recorded.push(partial);
+            };
+            {
+                YieldFor(1).await;
+                1
+            },
+            {
+                YieldFor(5).await;
+                2
+            },
+            {
+                YieldFor(2).await;
+                break 'outer;
+            }
+        );
+        unreachable!();
+    }
+    assert_eq!(recorded, [enjoin::Partial((Some(1), None, None))]);
+}
+
+#[pollster::test]
+async fn not_called_without_escape() {
+    let mut called = false;
+    let res = enjoin::join!(
+        on_escape = |_| called = true;
+        { 1 },
+        {
+            YieldFor(1).await;
+            2
+        }
+    );
+    assert_eq!(res, (1, 2));
+    assert!(!called);
+}
+
+#[pollster::test]
+async fn return_and_try() {
+    async fn inner(log: &mut Vec<Option<u8>>) -> Result<u8, &'static str> {
+        enjoin::try_join!(
+            on_escape = |partial| {
+                log.push(partial.into_inner().0);
+            };
+            { Ok::<_, ()>(3) },
+            {
+                YieldFor(1).await;
+                Err("early")?;
+                Ok(4)
+            }
+        )
+        .unwrap();
+        Ok(0)
+    }
+    let mut log = Vec::new();
+    assert_eq!(inner(&mut log).await, Err("early"));
+    assert_eq!(log, [Some(3)]);
+}
+
+#[pollster::test]
+async fn uses_shared_captures() {
+    let mut count = 0;
+    let mut seen = None;
+    'outer: loop {
+        enjoin::join_auto_borrow!(
+            on_escape = |partial| {
+                seen = Some((count, partial.0));
+            };
+            {
+                count += 1;
+            },
+            {
+                YieldFor(1).await;
+                count += 10;
+                break 'outer;
+            }
+        );
+    }
+    assert_eq!(seen, Some((11, (Some(()), None))));
+}
+
+#[pollster::test]
+async fn closure_type_is_inferred() {
+    let mut recorded = Vec::new();
+    'outer: loop {
+        enjoin::join!(
+            on_escape = |partial| recorded.push(partial.0.0);
+            { 5 },
+            {
+                YieldFor(1).await;
+                break 'outer;
+            }
+        );
+    }
+    assert_eq!(recorded, [Some(5)]);
+}
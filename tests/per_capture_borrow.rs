@@ -0,0 +1,84 @@
+mod utils;
+use utils::YieldFor;
+
+// The macro can't see the `.await` inside, so it can't release borrows around it.
+macro_rules! hidden_yield {
+    ($n:expr) => {
+        YieldFor($n).await
+    };
+}
+
+#[pollster::test]
+async fn only_borrow_used_captures() {
+    let mut a = Vec::new();
+    let mut b = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            a.push(1);
+            hidden_yield!(2);
+            a.push(2);
+        },
+        {
+            b.push(1);
+            YieldFor(1).await;
+            b.push(2);
+        },
+        {
+            b.push(3);
+            a.push(3);
+        }
+    );
+    assert_eq!(a, [1, 3, 2]);
+    assert_eq!(b, [1, 3, 2]);
+}
+
+#[pollster::test]
+async fn only_borrow_across_using_statements() {
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            log.push("a");
+            hidden_yield!(1);
+            log.push("c");
+        },
+        {
+            log.push("b");
+        }
+    );
+    assert_eq!(log, ["a", "b", "c"]);
+}
+
+#[pollster::test]
+async fn let_keeps_borrow() {
+    let mut v = vec![1, 2];
+    let mut total = 0;
+    enjoin::join_auto_borrow!(
+        {
+            let first = &mut v[0];
+            *first += 10;
+            total += *first;
+            YieldFor(1).await;
+            v.push(3);
+        },
+        {
+            v.push(4);
+            total += 1;
+        }
+    );
+    assert_eq!(v, [11, 2, 4, 3]);
+    assert_eq!(total, 12);
+}
+
+#[pollster::test]
+#[should_panic(expected = "`v` is shared between blocks and is already borrowed by another one")]
+async fn borrowed_across_hidden_await() {
+    let mut v = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            v.push(hidden_yield!(1));
+        },
+        {
+            v.push(());
+        }
+    );
+}
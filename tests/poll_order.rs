@@ -0,0 +1,97 @@
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn biased_race() {
+    let res = enjoin::race!(
+        biased;
+        {
+            YieldFor(1).await;
+            0
+        },
+        {
+            YieldFor(1).await;
+            1
+        }
+    );
+    assert_eq!(res, 0);
+}
+
+#[pollster::test]
+async fn rotate_race() {
+    // Both blocks are ready on the second poll, which starts from block 1.
+    let res = enjoin::race!(
+        rotate;
+        {
+            YieldFor(1).await;
+            0
+        },
+        {
+            YieldFor(1).await;
+            1
+        }
+    );
+    assert_eq!(res, 1);
+}
+
+#[pollster::test]
+async fn rotate_three() {
+    let mut wins = Vec::new();
+    for n in 0..3 {
+        let res = enjoin::race!(
+            rotate;
+            {
+                YieldFor(n).await;
+                0
+            },
+            {
+                YieldFor(n).await;
+                1
+            },
+            {
+                YieldFor(n).await;
+                2
+            }
+        );
+        wins.push(res);
+    }
+    assert_eq!(wins, [0, 1, 2]);
+}
+
+#[pollster::test]
+async fn rotate_join_order() {
+    let mut which = Vec::new();
+    enjoin::join_auto_borrow!(
+        rotate;
+        {
+            for _ in 0..3 {
+                which.push(0);
+                YieldFor(1).await;
+            }
+        },
+        {
+            for _ in 0..3 {
+                which.push(1);
+                YieldFor(1).await;
+            }
+        }
+    );
+    assert_eq!(which, [0, 1, 1, 0, 0, 1]);
+}
+
+#[pollster::test]
+async fn rotate_with_branch_wakers() {
+    let res = enjoin::race!(
+        rotate;
+        branch_wakers;
+        {
+            YieldFor(1).await;
+            0
+        },
+        {
+            YieldFor(1).await;
+            1
+        }
+    );
+    assert_eq!(res, 1);
+}
@@ -0,0 +1,139 @@
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn arms_handler() {
+    let res = enjoin::race!(
+        a = {
+            YieldFor(4).await;
+            4
+        } => a * 10,
+        b = {
+            YieldFor(2).await;
+            "two"
+        } => b.len(),
+    );
+    assert_eq!(res, 3);
+}
+
+#[pollster::test]
+async fn arms_mixed_with_blocks() {
+    let res = enjoin::race!(
+        {
+            YieldFor(2).await;
+            "block"
+        },
+        s = {
+            YieldFor(4).await;
+            String::from("arm")
+        } => {
+            drop(s);
+            "arm"
+        }
+    );
+    assert_eq!(res, "block");
+}
+
+#[pollster::test]
+async fn arms_refutable_pattern() {
+    let res = enjoin::race!(
+        Some(mut x) = {
+            YieldFor(1).await;
+            None::<i32>
+        } => {
+            x += 1;
+            x
+        }
+        Ok(x) = {
+            YieldFor(3).await;
+            Ok::<_, ()>(3)
+        } => x,
+    );
+    assert_eq!(res, 3);
+}
+
+#[pollster::test]
+async fn arms_else() {
+    let res = enjoin::race!(
+        Some(x) = {
+            YieldFor(1).await;
+            None::<i32>
+        } => x,
+        Some(x) = {
+            YieldFor(3).await;
+            None
+        } => x,
+        else => 100,
+    );
+    assert_eq!(res, 100);
+}
+
+#[pollster::test]
+async fn arms_handler_escape() {
+    let mut x = 0;
+    let res = loop {
+        x += 1;
+        enjoin::race!(
+            n = {
+                YieldFor(1).await;
+                x
+            } => {
+                if n < 3 {
+                    continue;
+                }
+                break n * 2;
+            },
+            {
+                YieldFor(10).await;
+            }
+        );
+    };
+    assert_eq!(res, 6);
+}
+
+#[pollster::test]
+async fn arms_handler_borrows() {
+    let mut v = vec![1];
+    enjoin::race!(
+        () = {
+            YieldFor(1).await;
+            assert_eq!(v.len(), 1);
+        } => v.push(2),
+        () = {
+            YieldFor(10).await;
+            assert_eq!(v.len(), 1);
+        } => v.push(3),
+    );
+    assert_eq!(v, [1, 2]);
+}
+
+#[pollster::test]
+async fn arms_handler_try() {
+    async fn inner() -> Result<i32, &'static str> {
+        let res = enjoin::race!(
+            r = {
+                YieldFor(1).await;
+                Err::<i32, _>("failed")
+            } => r?,
+        );
+        Ok(res)
+    }
+    assert_eq!(inner().await, Err("failed"));
+}
+
+#[pollster::test]
+async fn arms_block_escape() {
+    let res = 'a: {
+        enjoin::race!(
+            n = {
+                YieldFor(1).await;
+                break 'a 5;
+            } => n,
+            n = {
+                YieldFor(3).await;
+                3
+            } => n,
+        )
+    };
+    assert_eq!(res, 5);
+}
@@ -0,0 +1,65 @@
+#![allow(clippy::useless_vec)]
+
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn ref_methods_are_not_shared() {
+    let v = vec![1, 2, 3];
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            // `v` isn't in a cell, so this can be held across the await.
+            let first = v.first();
+            YieldFor(1).await;
+            log.push(*first.unwrap());
+        },
+        {
+            if !v.is_empty() && v.contains(&2) {
+                log.push(v.len() as i32);
+            }
+        }
+    );
+    assert_eq!(log, [3, 1]);
+}
+
+#[pollster::test]
+async fn ref_method_with_mutation_elsewhere() {
+    let mut v = vec![1];
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            log.push(v.len());
+            YieldFor(1).await;
+            log.push(v.len());
+        },
+        {
+            v.push(2);
+        }
+    );
+    assert_eq!(log, [1, 2]);
+}
+
+struct Queue(Vec<u8>);
+impl Queue {
+    fn peek(&self) -> Option<&u8> {
+        self.0.last()
+    }
+}
+
+#[pollster::test]
+async fn listed_ref_methods() {
+    let queue = Queue(vec![1, 2]);
+    let (a, b) = enjoin::join_auto_borrow!(
+        ref_methods(peek);
+        {
+            let top = queue.peek();
+            YieldFor(1).await;
+            top.copied()
+        },
+        {
+            queue.peek().copied()
+        }
+    );
+    assert_eq!((a, b), (Some(2), Some(2)));
+}
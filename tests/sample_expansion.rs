@@ -12,7 +12,7 @@ async fn sample_expansion() {
         'a: {
             #[allow(warnings)]
             {
-                let __enjoin_borrows_cell = ::std::cell::RefCell::new((&mut done,));
+                let __enjoin_copy_cell_0 = ::enjoin::__private::Cell::from_mut(&mut done);
                 enum __enjoin_OutputEnum<__enjoin_Return, __enjoin_Keep> {
                     __enjoin_Keep(__enjoin_Keep),
                     __enjoin_Return(__enjoin_Return),
@@ -42,26 +42,21 @@ async fn sample_expansion() {
                         __enjoin_OutputEnum::__enjoin_Keep(
                             #[warn(unreachable_code)]
                             {
-                                let mut __enjoin_borrows =
-                                    ::std::cell::RefCell::borrow_mut(&__enjoin_borrows_cell);
-                                let res = (
-                                    (do_thing_a(), {
-                                        ::core::mem::drop(__enjoin_borrows);
-                                    })
-                                        .0
-                                        .await,
-                                    {
-                                        __enjoin_borrows = ::std::cell::RefCell::borrow_mut(
-                                            &__enjoin_borrows_cell,
-                                        );
-                                    },
-                                )
-                                    .0;
+                                let res = do_thing_a().await;
                                 if res > 3 {
                                     return __enjoin_OutputEnum::__enjoin_Return((Some("hello")));
                                 } else {
-                                    (*__enjoin_borrows.0) += 1;
-                                    if (*__enjoin_borrows.0) == 2 {
+                                    {
+                                        let __enjoin_copy_cell_rhs = 1;
+                                        let mut __enjoin_copy_cell_value =
+                                            ::enjoin::__private::Cell::get(__enjoin_copy_cell_0);
+                                        __enjoin_copy_cell_value += __enjoin_copy_cell_rhs;
+                                        ::enjoin::__private::Cell::set(
+                                            __enjoin_copy_cell_0,
+                                            __enjoin_copy_cell_value,
+                                        )
+                                    };
+                                    if ::enjoin::__private::Cell::get(__enjoin_copy_cell_0) == 2 {
                                         return __enjoin_OutputEnum::__enjoin_Break_a(());
                                     }
                                 }
@@ -73,31 +68,27 @@ async fn sample_expansion() {
                         __enjoin_OutputEnum::__enjoin_Keep(
                             #[warn(unreachable_code)]
                             {
-                                let mut __enjoin_borrows =
-                                    ::std::cell::RefCell::borrow_mut(&__enjoin_borrows_cell);
-                                let _res = (match ::enjoin::polyfill::Try::branch(
-                                    (
-                                        (do_thing_b(), {
-                                            ::core::mem::drop(__enjoin_borrows);
-                                        })
-                                            .0
-                                            .await,
-                                        {
-                                            __enjoin_borrows = ::std::cell::RefCell::borrow_mut(
-                                                &__enjoin_borrows_cell,
-                                            );
-                                        },
+                                let _res =
+                                    (match ::enjoin::polyfill::Try::branch(do_thing_b().await) {
+                                        ::core::ops::ControlFlow::Break(b) => {
+                                            return __enjoin_OutputEnum::__enjoin_Return(
+                                                (::enjoin::polyfill::FromResidual::from_residual(
+                                                    b,
+                                                )),
+                                            )
+                                        }
+                                        ::core::ops::ControlFlow::Continue(c) => c,
+                                    });
+                                {
+                                    let __enjoin_copy_cell_rhs = 1;
+                                    let mut __enjoin_copy_cell_value =
+                                        ::enjoin::__private::Cell::get(__enjoin_copy_cell_0);
+                                    __enjoin_copy_cell_value += __enjoin_copy_cell_rhs;
+                                    ::enjoin::__private::Cell::set(
+                                        __enjoin_copy_cell_0,
+                                        __enjoin_copy_cell_value,
                                     )
-                                        .0,
-                                ) {
-                                    ::core::ops::ControlFlow::Break(b) => {
-                                        return __enjoin_OutputEnum::__enjoin_Return(
-                                            (::enjoin::polyfill::FromResidual::from_residual(b)),
-                                        )
-                                    }
-                                    ::core::ops::ControlFlow::Continue(c) => c,
-                                });
-                                (*__enjoin_borrows.0) += 1;
+                                };
                             },
                         )
                     }),
@@ -0,0 +1,156 @@
+mod utils;
+use utils::YieldFor;
+
+struct Worker {
+    a: Vec<i32>,
+    b: Vec<i32>,
+    total: i32,
+}
+
+impl Worker {
+    const STEP: i32 = 10;
+
+    fn new() -> Self {
+        Self {
+            a: Vec::new(),
+            b: Vec::new(),
+            total: 0,
+        }
+    }
+
+    fn record(&mut self, x: i32) {
+        self.total += x;
+    }
+
+    fn sum(&self) -> i32 {
+        self.a.iter().chain(self.b.iter()).sum()
+    }
+
+    async fn disjoint_fields(&mut self) {
+        // `self.a` and `self.b` aren't shared, so they can be borrowed across awaits.
+        enjoin::join_auto_borrow!(
+            {
+                let a = &mut self.a;
+                a.push(1);
+                YieldFor(1).await;
+                a.push(Self::STEP);
+            },
+            {
+                let b = &mut self.b;
+                b.push(2);
+                YieldFor(1).await;
+                b.push(Worker::STEP);
+            }
+        );
+    }
+
+    async fn same_field(&mut self) {
+        let b = &mut self.b;
+        enjoin::join_auto_borrow!(
+            {
+                self.a.push(1);
+                YieldFor(1).await;
+                self.a.push(3);
+            },
+            {
+                self.a.push(2);
+                b.push(Self::STEP);
+            }
+        );
+    }
+
+    async fn methods_on_self(&mut self) -> i32 {
+        let (_, sum) = enjoin::join_auto_borrow!(
+            {
+                self.record(1);
+                YieldFor(1).await;
+                self.record(Self::STEP);
+            },
+            {
+                self.a.push(5);
+                YieldFor(2).await;
+                self.sum()
+            }
+        );
+        sum
+    }
+
+    async fn listed(&mut self) {
+        enjoin::join_auto_borrow!(
+            share(mut self.total),
+            {
+                self.total += 1;
+                YieldFor(1).await;
+                self.a.push(self.total);
+            },
+            {
+                self.total += 1;
+            }
+        );
+    }
+
+    async fn by_value(mut self) -> i32 {
+        enjoin::join_auto_borrow!(
+            {
+                self.record(1);
+                YieldFor(1).await;
+            },
+            {
+                self.record(1);
+            }
+        );
+        self.total
+    }
+
+    async fn in_join_for(&mut self) {
+        enjoin::join_for_auto_borrow!(for i in 0..3 {
+            self.a.push(i);
+            YieldFor(1).await;
+            self.record(i);
+        });
+    }
+}
+
+#[pollster::test]
+async fn self_disjoint_fields() {
+    let mut w = Worker::new();
+    w.disjoint_fields().await;
+    assert_eq!(w.a, [1, 10]);
+    assert_eq!(w.b, [2, 10]);
+}
+
+#[pollster::test]
+async fn self_same_field() {
+    let mut w = Worker::new();
+    w.same_field().await;
+    assert_eq!(w.a, [1, 2, 3]);
+    assert_eq!(w.b, [10]);
+}
+
+#[pollster::test]
+async fn self_methods() {
+    let mut w = Worker::new();
+    let sum = w.methods_on_self().await;
+    assert_eq!(sum, 5);
+    assert_eq!(w.total, 11);
+}
+
+#[pollster::test]
+async fn self_in_share_list() {
+    let mut w = Worker::new();
+    w.listed().await;
+    assert_eq!(w.a, [2]);
+}
+
+#[pollster::test]
+async fn self_in_join_for() {
+    let mut w = Worker::new();
+    w.in_join_for().await;
+    assert_eq!(w.a, [0, 1, 2]);
+    assert_eq!(w.total, 3);
+}
+
+#[pollster::test]
+async fn self_by_value() {
+    assert_eq!(Worker::new().by_value().await, 2);
+}
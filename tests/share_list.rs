@@ -0,0 +1,91 @@
+mod utils;
+use utils::YieldFor;
+
+struct State {
+    log: Vec<&'static str>,
+    count: i32,
+}
+
+#[pollster::test]
+async fn share_mut_and_field() {
+    let mut a = Vec::new();
+    let mut state = State {
+        log: Vec::new(),
+        count: 0,
+    };
+    enjoin::join_auto_borrow!(
+        share(mut a, mut state.log),
+        {
+            a.push(1);
+            state.log.push("first");
+            YieldFor(1).await;
+            a.push(3);
+        },
+        {
+            a.push(2);
+            state.log.push("second");
+            state.count += 1;
+        }
+    );
+    assert_eq!(a, [1, 2, 3]);
+    assert_eq!(state.log, ["first", "second"]);
+    assert_eq!(state.count, 1);
+}
+
+#[pollster::test]
+async fn share_immutable_across_await() {
+    let v = [1, 2, 3];
+    let mut total = 0;
+    enjoin::join_auto_borrow!(
+        share(v, mut total);
+        {
+            let first = v.first();
+            YieldFor(1).await;
+            total += first.unwrap();
+        },
+        {
+            total += v.len() as i32;
+        }
+    );
+    assert_eq!(total, 4);
+}
+
+#[pollster::test]
+async fn share_capitalized() {
+    #[allow(non_snake_case)]
+    let mut Log = Vec::new();
+    enjoin::join_auto_borrow!(
+        share(mut Log);
+        {
+            Log.push(1);
+            YieldFor(1).await;
+            Log.push(3);
+        },
+        {
+            Log.push(2);
+        }
+    );
+    assert_eq!(Log, [1, 2, 3]);
+}
+
+#[pollster::test]
+async fn share_call_argument() {
+    fn bump(x: &mut i32) {
+        *x += 1;
+    }
+    let mut count = 0;
+    let name = String::from("name");
+    enjoin::join_auto_borrow!(
+        share(mut count, move name);
+        {
+            bump(&mut count);
+            YieldFor(1).await;
+            bump(&mut count);
+            drop(name);
+        },
+        {
+            bump(&mut count);
+        }
+    );
+    assert_eq!(count, 3);
+}
@@ -0,0 +1,193 @@
+mod utils;
+use utils::YieldFor;
+
+#[pollster::test]
+async fn cell_counter() {
+    let mut count = 0;
+    let mut max = 0;
+    enjoin::join_auto_borrow!(
+        {
+            for _ in 0..5 {
+                count += 1;
+                if count > max {
+                    max = count;
+                }
+                YieldFor(1).await;
+            }
+        },
+        {
+            max = -1;
+            for _ in 0..3 {
+                count -= 1;
+                YieldFor(2).await;
+            }
+        }
+    );
+    assert_eq!(count, 2);
+    assert_eq!(max, 3);
+}
+
+#[pollster::test]
+async fn cell_update_with_await() {
+    let mut total = 0;
+    enjoin::join_auto_borrow!(
+        {
+            total += {
+                YieldFor(2).await;
+                total + 10
+            };
+        },
+        {
+            YieldFor(1).await;
+            total *= 3;
+            total += 1;
+        }
+    );
+    assert_eq!(total, 12);
+}
+
+#[pollster::test]
+async fn cell_flag() {
+    let mut stop = false;
+    let mut ticks = 0;
+    enjoin::join_auto_borrow!(
+        {
+            while !stop {
+                ticks += 1;
+                YieldFor(1).await;
+            }
+        },
+        {
+            YieldFor(3).await;
+            stop = true;
+        }
+    );
+    assert!(stop);
+    assert_eq!(ticks, 4);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+#[pollster::test]
+async fn cell_copy_annotation() {
+    let mut pos = Point { x: 0, y: 0 };
+    let mut last = Point { x: 0, y: 0 };
+    enjoin::join_auto_borrow!(
+        copy(pos);
+        {
+            for i in 1..3 {
+                pos = Point { x: i, y: i * 2 };
+                YieldFor(1).await;
+            }
+        },
+        {
+            for _ in 0..3 {
+                last = pos;
+                YieldFor(1).await;
+            }
+        }
+    );
+    assert_eq!(pos, Point { x: 2, y: 4 });
+    assert_eq!(last, pos);
+}
+
+#[pollster::test]
+async fn cell_alongside_refcell() {
+    let mut count = 0;
+    let mut log = Vec::new();
+    enjoin::join_auto_borrow!(
+        {
+            count += 1;
+            log.push(count);
+            YieldFor(1).await;
+            count += 1;
+            log.push(count);
+        },
+        {
+            count += 10;
+            log.push(count);
+        }
+    );
+    assert_eq!(log, [1, 11, 12]);
+}
+
+#[pollster::test]
+async fn cell_join_for() {
+    let mut sum = 0;
+    enjoin::join_for_auto_borrow!(copy(sum); for i in 0..4 {
+        YieldFor(i).await;
+        sum += i;
+    });
+    assert_eq!(sum, 6);
+}
+
+#[derive(Debug, PartialEq)]
+struct Big(Vec<u32>);
+
+impl std::ops::AddAssign<u32> for Big {
+    fn add_assign(&mut self, rhs: u32) {
+        self.0.push(rhs);
+    }
+}
+
+#[pollster::test]
+async fn compound_assign_of_non_copy() {
+    let mut big = Big(Vec::new());
+    enjoin::join_auto_borrow!(
+        {
+            big += 1;
+            YieldFor(1).await;
+            big += 3;
+        },
+        {
+            big += 2;
+        }
+    );
+    assert_eq!(big, Big(vec![1, 2, 3]));
+}
+
+// Hides the `.await` from the macro, so it can't release the borrows around it.
+macro_rules! hidden_yield {
+    () => {
+        YieldFor(1).await
+    };
+}
+
+#[pollster::test]
+#[should_panic(expected = "`count` is shared between blocks and is already borrowed")]
+async fn compound_assign_alone_uses_refcell() {
+    let mut count = 0;
+    enjoin::join_auto_borrow!(
+        {
+            count += {
+                hidden_yield!();
+                1
+            };
+        },
+        {
+            count += 1;
+        }
+    );
+}
+
+#[pollster::test]
+async fn compound_assign_alone_listed_in_copy() {
+    let mut count = 0;
+    enjoin::join_auto_borrow!(
+        copy(count);
+        {
+            count += {
+                hidden_yield!();
+                1
+            };
+        },
+        {
+            count += 1;
+        }
+    );
+    assert_eq!(count, 2);
+}
@@ -0,0 +1,144 @@
+#![allow(clippy::never_loop)]
+
+mod utils;
+use std::time::Duration;
+
+use utils::YieldFor;
+
+/// Each millisecond is one yield.
+struct Yields;
+
+impl enjoin::Timer for Yields {
+    fn sleep(&self, duration: Duration) -> impl core::future::Future<Output = ()> {
+        YieldFor(duration.as_millis() as usize)
+    }
+}
+
+#[pollster::test]
+async fn race_times_out() {
+    let res = enjoin::race!(
+        {
+            YieldFor(5).await;
+            "done"
+        },
+        timeout(Duration::from_millis(2), Yields) => "timed out",
+    );
+    assert_eq!(res, "timed out");
+}
+
+#[pollster::test]
+async fn race_finishes_in_time() {
+    let res = enjoin::race!(
+        {
+            YieldFor(1).await;
+            "done"
+        },
+        timeout(Duration::from_millis(2), Yields) => "timed out",
+    );
+    assert_eq!(res, "done");
+}
+
+#[pollster::test]
+async fn block_wins_a_tie() {
+    let res = enjoin::race!(
+        {
+            YieldFor(2).await;
+            "done"
+        },
+        timeout(Duration::from_millis(2), &Yields) => "timed out",
+    );
+    assert_eq!(res, "done");
+}
+
+#[pollster::test]
+async fn join_escapes_from_handler() {
+    let mut slow = Vec::new();
+    'outer: loop {
+        let res = enjoin::join_auto_borrow!(
+            {
+                YieldFor(1).await;
+                1
+            } on_cancel {
+                slow.push("a");
+            },
+            {
+                YieldFor(10).await;
+                2
+            } on_cancel {
+                slow.push("b");
+            },
+            timeout(Duration::from_millis(3), Yields) => {
+                slow.push("timed out");
+                break 'outer;
+            }
+        );
+        unreachable!("{res:?}");
+    }
+    assert_eq!(slow, ["b", "timed out"]);
+}
+
+#[pollster::test]
+async fn join_finishes_in_time() {
+    let res = enjoin::join!(
+        { 1 },
+        {
+            YieldFor(1).await;
+            2
+        },
+        timeout(Duration::from_millis(3), Yields) => (0, 0),
+    );
+    assert_eq!(res, (1, 2));
+}
+
+#[pollster::test]
+async fn try_join_returns_from_handler() {
+    async fn inner() -> Result<(u8, u8), &'static str> {
+        let res = enjoin::try_join!(
+            { Ok::<_, &'static str>(1) },
+            {
+                YieldFor(5).await;
+                Ok(2)
+            },
+            timeout(Duration::from_millis(1), Yields) => return Err("too slow"),
+        )?;
+        Ok(res)
+    }
+    assert_eq!(inner().await, Err("too slow"));
+}
+
+#[pollster::test]
+async fn race_arms_with_timeout() {
+    let res = enjoin::race!(
+        Some(x) = {
+            YieldFor(1).await;
+            None::<u8>
+        } => x,
+        timeout(Duration::from_millis(3), Yields) => 0,
+        else => 100,
+    );
+    assert_eq!(res, 100);
+    let res = enjoin::race!(
+        Some(x) = {
+            YieldFor(5).await;
+            Some(1)
+        } => x,
+        timeout(Duration::from_millis(3), Yields) => 0,
+    );
+    assert_eq!(res, 0);
+}
+
+#[pollster::test]
+async fn with_on_escape() {
+    let mut partial = None;
+    enjoin::join!(
+        on_escape = |p| partial = Some(p.0);
+        { 1 },
+        {
+            YieldFor(5).await;
+            2
+        },
+        timeout(Duration::from_millis(1), Yields) => (0, 0),
+    );
+    // Timing out is not an escape.
+    assert_eq!(partial, None);
+}
@@ -0,0 +1,17 @@
+// The notes are deprecation warnings; deny them to capture their text.
+#![deny(deprecated)]
+
+fn main() {
+    let _ = async {
+        let mut count = 0;
+        enjoin::join_auto_borrow!(
+            explain;
+            {
+                count += 1;
+            },
+            {
+                count = 0;
+            }
+        );
+    };
+}
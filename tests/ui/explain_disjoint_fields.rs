@@ -0,0 +1,29 @@
+// The notes are deprecation warnings; deny them to capture their text.
+#![deny(deprecated)]
+
+struct State {
+    a: Vec<i32>,
+    b: Vec<i32>,
+    c: Vec<i32>,
+}
+
+fn main() {
+    let _ = async {
+        let mut s = State {
+            a: Vec::new(),
+            b: Vec::new(),
+            c: Vec::new(),
+        };
+        enjoin::join_auto_borrow!(
+            explain;
+            {
+                s.a.push(1);
+                s.c.push(1);
+            },
+            {
+                s.b.push(2);
+                s.c.push(2);
+            }
+        );
+    };
+}
@@ -0,0 +1,18 @@
+// The notes are deprecation warnings; deny them to capture their text.
+#![deny(deprecated)]
+
+fn main() {
+    let _ = async {
+        let mut log = Vec::new();
+        let name = String::new();
+        enjoin::join_auto_borrow!(
+            explain;
+            {
+                log.push(name.len());
+            },
+            {
+                log.push(name.len());
+            }
+        );
+    };
+}